@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use futures::task::AtomicWaker;
+
+/// Create a one-shot reply channel.
+///
+/// `Reply` is supposed to be carried by a message, so that the fsm handling
+/// the message can send the response back to the `AskHandle`.
+pub fn reply_channel<T>() -> (Reply<T>, AskHandle<T>) {
+    let (tx, rx) = channel::bounded(1);
+    let waker = Arc::new(AtomicWaker::new());
+    (
+        Reply {
+            sender: Some(tx),
+            waker: waker.clone(),
+        },
+        AskHandle {
+            receiver: rx,
+            waker,
+            deadline: None,
+            armed: false,
+        },
+    )
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AskError {
+    /// No response is received in time.
+    Timeout,
+    /// The reply is dropped without sending a response.
+    Dropped,
+}
+
+impl fmt::Display for AskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AskError::Timeout => write!(f, "ask timeout"),
+            AskError::Dropped => write!(f, "reply dropped"),
+        }
+    }
+}
+
+impl std::error::Error for AskError {}
+
+//region Reply
+/// The sending half of a reply channel.
+pub struct Reply<T> {
+    sender: Option<channel::Sender<T>>,
+    waker: Arc<AtomicWaker>,
+}
+
+impl<T> Reply<T> {
+    /// Send the response. Returns false if the asker has gone.
+    pub fn send(mut self, value: T) -> bool {
+        self.sender.take().unwrap().send(value).is_ok()
+    }
+}
+
+impl<T> Drop for Reply<T> {
+    fn drop(&mut self) {
+        // The sender must be dropped before waking up the asker, otherwise
+        // it may see neither a response nor a disconnected channel.
+        drop(self.sender.take());
+        self.waker.wake();
+    }
+}
+
+//endregion
+
+//region AskHandle
+/// The receiving half of a reply channel.
+///
+/// It can either be waited on with a timeout, or be awaited as a future.
+pub struct AskHandle<T> {
+    receiver: channel::Receiver<T>,
+    waker: Arc<AtomicWaker>,
+    // When the future resolves to `AskError::Timeout`, if any.
+    deadline: Option<Instant>,
+    // Whether the deadline thread is asked to wake up the future.
+    armed: bool,
+}
+
+impl<T> AskHandle<T> {
+    /// Make the future resolve to `AskError::Timeout` if no response is
+    /// received in `timeout`. It doesn't affect `wait`.
+    pub fn with_timeout(mut self, timeout: Duration) -> AskHandle<T> {
+        self.deadline = Some(Instant::now() + timeout);
+        self.armed = false;
+        self
+    }
+
+    /// Block current thread until the response is received.
+    pub fn wait(&self, timeout: Duration) -> Result<T, AskError> {
+        self.receiver.recv_timeout(timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => AskError::Timeout,
+            RecvTimeoutError::Disconnected => AskError::Dropped,
+        })
+    }
+}
+
+// The response is never pinned, it's only moved out of the channel.
+impl<T> Unpin for AskHandle<T> {}
+
+impl<T> Future for AskHandle<T> {
+    type Output = Result<T, AskError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match this.receiver.try_recv() {
+            Ok(v) => return Poll::Ready(Ok(v)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(Err(AskError::Dropped)),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        this.waker.register(cx.waker());
+        match this.receiver.try_recv() {
+            Ok(v) => return Poll::Ready(Ok(v)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(Err(AskError::Dropped)),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        match this.deadline {
+            Some(deadline) if deadline <= Instant::now() => Poll::Ready(Err(AskError::Timeout)),
+            Some(deadline) => {
+                if !this.armed {
+                    this.armed = true;
+                    wake_at(deadline, Arc::downgrade(&this.waker));
+                }
+                Poll::Pending
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+//endregion
+
+//region Deadlines
+type Wakeup = (Instant, Weak<AtomicWaker>);
+
+// Wake up the asker at `deadline`, unless it has gone by then. All deadlines
+// are served by a single thread, which is started on demand and never stops.
+fn wake_at(deadline: Instant, waker: Weak<AtomicWaker>) {
+    static DEADLINES: Mutex<Option<Sender<Wakeup>>> = Mutex::new(None);
+    let mut deadlines = DEADLINES.lock().unwrap();
+    let tx = deadlines.get_or_insert_with(|| {
+        let (tx, rx) = channel::unbounded();
+        thread::Builder::new()
+            .name("ask-deadline".to_owned())
+            .spawn(move || run_deadlines(rx))
+            .unwrap();
+        tx
+    });
+    tx.send((deadline, waker)).unwrap();
+}
+
+fn run_deadlines(rx: Receiver<Wakeup>) {
+    // Keyed by deadlines, and a sequence number to keep equal ones apart.
+    let mut pending = BTreeMap::new();
+    let mut seq = 0u64;
+    loop {
+        let next = match pending.keys().next() {
+            Some(&(deadline, _)) => rx.recv_deadline(deadline),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match next {
+            Ok((deadline, waker)) => {
+                seq += 1;
+                pending.insert((deadline, seq), waker);
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let now = Instant::now();
+                while let Some(&key) = pending.keys().next() {
+                    if key.0 > now {
+                        break;
+                    }
+                    if let Some(waker) = pending.remove(&key).unwrap().upgrade() {
+                        waker.wake();
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+//endregion
@@ -1,40 +1,73 @@
 use std::time::{Instant, Duration};
-use crate::tikv_batch::fsm::{Fsm, FsmScheduler, Priority};
+use crate::tikv_batch::fsm::{Fsm, FsmScheduler, PerPriority, Priority};
 use std::borrow::{Cow, Borrow, BorrowMut};
 use crate::tikv_batch::mpsc::{Sender, LooseBoundedSender, Receiver};
-use crate::tikv_batch::router::Router;
+use crate::tikv_batch::router::{FsmFactory, Router};
 use crate::tikv_batch::config::Config;
 use crate::tikv_batch::mailbox::BasicMailbox;
+use crate::tikv_batch::metrics::{BatchMetrics, MetricsSnapshot, PollerMetrics};
+use crate::tikv_batch::reschedule::{BatchStats, ReschedulePolicy};
+use crate::tikv_batch::supervisor::{FailurePolicy, FsmFailure, Supervisor};
 use std::ops::Deref;
 use std::thread::JoinHandle;
-use crossbeam::channel::{self, SendError};
+use crossbeam::channel::{self, SendError, TryRecvError};
+use crossbeam::deque::{self, Steal, Stealer};
 use crate::tikv_batch::util;
+use crate::tikv_batch::util::thread_group::GroupProperties;
 use std::thread;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::AtomicUsize;
 use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
 
 enum FsmTypes<N, C> {
     Normal(Box<N>),
     Control(Box<C>),
-    /// Used as a signal that scheduler should be shutdown.
-    Empty,
 }
 
+//region StopSignal
+/// A signal that tells every poller of a batch system to exit.
+///
+/// Pollers check the shutdown flag of their thread group between rounds. Idle
+/// pollers block on the receiving side of `sender`, which is disconnected when
+/// the signal is triggered, so all of them wake up no matter how many there are.
+#[derive(Clone)]
+struct StopSignal {
+    props: GroupProperties,
+    sender: Arc<Mutex<Option<channel::Sender<()>>>>,
+}
+
+impl StopSignal {
+    fn new() -> (StopSignal, channel::Receiver<()>) {
+        let (tx, rx) = channel::bounded(0);
+        let signal = StopSignal {
+            props: GroupProperties::default(),
+            sender: Arc::new(Mutex::new(Some(tx))),
+        };
+        (signal, rx)
+    }
+
+    fn trigger(&self) {
+        self.props.mark_shutdown();
+        self.sender.lock().unwrap().take();
+    }
+}
 
+//endregion
 
 //region NormalScheduler
 pub struct NormalScheduler<N, C> {
-    sender: channel::Sender<FsmTypes<N, C>>,
-    low_sender: channel::Sender<FsmTypes<N, C>>,
+    // A channel for every priority class, indexed by `Priority::index`.
+    senders: Vec<channel::Sender<FsmTypes<N, C>>>,
+    stop: StopSignal,
 }
 
 impl<N, C> Clone for NormalScheduler<N, C> {
     #[inline]
     fn clone(&self) -> Self {
         NormalScheduler {
-            sender: self.sender.clone(),
-            low_sender: self.low_sender.clone(),
+            senders: self.senders.clone(),
+            stop: self.stop.clone(),
         }
     }
 }
@@ -44,10 +77,7 @@ impl<N, C> FsmScheduler for NormalScheduler<N, C>
     type Fsm = N;
 
     fn schedule(&self, fsm: Box<Self::Fsm>) {
-        let sender = match fsm.get_priority() {
-            Priority::Normal => &self.sender,
-            Priority::Low => &self.low_sender,
-        };
+        let sender = &self.senders[fsm.get_priority().index()];
 
         match sender.send(FsmTypes::Normal(fsm)) {
             Ok(()) => {}
@@ -57,10 +87,7 @@ impl<N, C> FsmScheduler for NormalScheduler<N, C>
     }
 
     fn shutdown(&self) {
-        for _ in 0..100 {
-            let _ = self.sender.send(FsmTypes::Empty);
-            let _ = self.low_sender.send(FsmTypes::Empty);
-        }
+        self.stop.trigger();
     }
 }
 
@@ -69,16 +96,17 @@ impl<N, C> FsmScheduler for NormalScheduler<N, C>
 
 //region ControlScheduler
 pub struct ControlScheduler<N, C> {
-    sender: channel::Sender<FsmTypes<N, C>>,
-    low_sender: channel::Sender<FsmTypes<N, C>>,
+    // A channel for every priority class, indexed by `Priority::index`.
+    senders: Vec<channel::Sender<FsmTypes<N, C>>>,
+    stop: StopSignal,
 }
 
 impl<N, C> Clone for ControlScheduler<N, C> {
     #[inline]
     fn clone(&self) -> Self {
         ControlScheduler {
-            sender: self.sender.clone(),
-            low_sender: self.low_sender.clone(),
+            senders: self.senders.clone(),
+            stop: self.stop.clone(),
         }
     }
 }
@@ -88,10 +116,7 @@ impl<N, C> FsmScheduler for ControlScheduler<N, C>
     type Fsm = C;
 
     fn schedule(&self, fsm: Box<Self::Fsm>) {
-        let sender = match fsm.get_priority() {
-            Priority::Normal => &self.sender,
-            Priority::Low => &self.low_sender,
-        };
+        let sender = &self.senders[fsm.get_priority().index()];
 
         match sender.send(FsmTypes::Control(fsm)) {
             Ok(()) => {}
@@ -101,10 +126,7 @@ impl<N, C> FsmScheduler for ControlScheduler<N, C>
     }
 
     fn shutdown(&self) {
-        for _ in 0..100 {
-            let _ = self.sender.send(FsmTypes::Empty);
-            let _ = self.low_sender.send(FsmTypes::Empty);
-        }
+        self.stop.trigger();
     }
 }
 //endregion
@@ -115,7 +137,8 @@ impl<N, C> FsmScheduler for ControlScheduler<N, C>
 //     ($name:ident,$ty:path,Fsm=$fsm:tt) =>{
 //         pub struct $name<N,C>{
 //             sender:Sender<FsmTypes<N,C>>,
-//             low_sender:Sender<FsmTypes<N,C>>
+//             low_sender:Sender<FsmTypes<N,C>>,
+//             stop:StopSignal,
 //         }
 //
 //         impl<N,C> Clone for $name<N,C>{
@@ -124,6 +147,7 @@ impl<N, C> FsmScheduler for ControlScheduler<N, C>
 //                 $name{
 //                     sender:self.sender.clone(),
 //                     low_sender:self.low_sender.clone(),
+//                     stop:self.stop.clone(),
 //                 }
 //             }
 //         }
@@ -148,10 +172,7 @@ impl<N, C> FsmScheduler for ControlScheduler<N, C>
 //             }
 //
 //             pub fn shutdown(&self){
-//                 for _ in 0..100{
-//                     let _ =self.sender.send(FsmTypes::Empty);
-//                     let _ =self.low_sender.send(FsmTypes::Empty);
-//                 }
+//                 self.stop.trigger();
 //             }
 //
 //
@@ -169,7 +190,7 @@ impl<N, C> FsmScheduler for ControlScheduler<N, C>
 
 pub struct Batch<N, C> {
     normals: Vec<Box<N>>,
-    timers: Vec<Instant>,
+    stats: Vec<BatchStats>,
     control: Option<Box<C>>,
 }
 
@@ -177,43 +198,61 @@ impl<N: Fsm, C: Fsm> Batch<N, C> {
     pub fn with_capacity(cap: usize) -> Batch<N, C> {
         Batch {
             normals: Vec::with_capacity(cap),
-            timers: Vec::with_capacity(cap),
+            stats: Vec::with_capacity(cap),
             control: None,
         }
     }
 
-    fn push(&mut self, fsm: FsmTypes<N, C>) -> bool {
+    fn push(&mut self, fsm: FsmTypes<N, C>) {
         match fsm {
             FsmTypes::Normal(n) => {
                 self.normals.push(n);
-                self.timers.push(Instant::now())
+                self.stats.push(BatchStats::new())
             }
             FsmTypes::Control(c) => {
                 assert!(self.control.is_none());
                 self.control = Some(c);
             }
-            FsmTypes::Empty => return false,
         }
-        true
     }
 
-    fn is_empty(&self) -> bool {
-        self.normals.is_empty() && self.control.is_none()
+    fn push_ready(&mut self, fsm: Box<N>, stats: BatchStats) {
+        self.normals.push(fsm);
+        self.stats.push(stats);
+    }
+
+    /// Take all normal FSMs out of the batch, with their stats in it.
+    fn take_ready(&mut self) -> impl Iterator<Item = ReadyFsm<N>> + '_ {
+        self.normals.drain(..).zip(self.stats.drain(..))
     }
 
     fn clear(&mut self) {
-        self.normals.clear();
-        self.timers.clear();
-        self.control.take();
+        for mut fsm in self.normals.drain(..) {
+            fsm.on_drop();
+        }
+        self.stats.clear();
+        if let Some(mut control) = self.control.take() {
+            control.on_drop();
+        }
     }
 
     pub fn release(&mut self, index: usize, checked_len: usize) {
         let mut fsm = self.normals.swap_remove(index);
         let mailbox = fsm.take_mailbox().unwrap();
+        // Messages that arrived meanwhile keep the FSM in the batch, it never
+        // goes idle.
+        if mailbox.len() != checked_len {
+            fsm.set_mailbox(Cow::Owned(mailbox));
+            let last_index = self.normals.len();
+            self.normals.push(fsm);
+            self.normals.swap(index, last_index);
+            return;
+        }
+        mailbox.idle(&mut fsm);
         mailbox.release(fsm);
 
         if mailbox.len() == checked_len {
-            self.timers.swap_remove(index);
+            self.stats.swap_remove(index);
         } else {
             match mailbox.take_fsm() {
                 None => {}
@@ -231,8 +270,9 @@ impl<N: Fsm, C: Fsm> Batch<N, C> {
         let mut fsm = self.normals.swap_remove(index);
         let mailbox = fsm.take_mailbox().unwrap();
         if mailbox.is_empty() {
+            mailbox.stop(&mut fsm);
             mailbox.release(fsm);
-            self.timers.swap_remove(index);
+            self.stats.swap_remove(index);
         } else {
             fsm.set_mailbox(Cow::Owned(mailbox));
             let last_index = self.normals.len();
@@ -243,12 +283,16 @@ impl<N: Fsm, C: Fsm> Batch<N, C> {
 
     pub fn reschedule(&mut self, router: &BatchRouter<N, C>, index: usize) {
         let fsm = self.normals.swap_remove(index);
-        self.timers.swap_remove(index);
+        self.stats.swap_remove(index);
         router.normal_scheduler.schedule(fsm);
     }
 
     pub fn release_control(&mut self, control_box: &BasicMailbox<C>, checked_len: usize) -> bool {
-        let s = self.control.take().unwrap();
+        if control_box.len() != checked_len {
+            return false;
+        }
+        let mut s = self.control.take().unwrap();
+        control_box.idle(&mut s);
         control_box.release(s);
         if control_box.len() == checked_len {
             true
@@ -265,10 +309,69 @@ impl<N: Fsm, C: Fsm> Batch<N, C> {
 
     pub fn remove_control(&mut self, control_box: &BasicMailbox<C>) {
         if control_box.is_empty() {
-            let s = self.control.take().unwrap();
+            let mut s = self.control.take().unwrap();
+            control_box.stop(&mut s);
             control_box.release(s);
         }
     }
+
+    /// Hands every FSM in the batch back to the schedulers, so that other
+    /// pollers can pick them up.
+    fn hand_back(&mut self, router: &BatchRouter<N, C>) {
+        self.stats.clear();
+        for fsm in self.normals.drain(..) {
+            router.normal_scheduler.schedule(fsm);
+        }
+        if let Some(control) = self.control.take() {
+            router.control_scheduler.schedule(control);
+        }
+    }
+}
+
+//endregion
+
+//region BatchSizer
+const MIN_BATCH_SIZE: usize = 1;
+
+/// Chooses the batch size of every round. Without a time budget, it's always
+/// the max batch size. Otherwise it's tuned after every round, so that
+/// handling normal FSMs in a round takes about the time budget.
+struct BatchSizer {
+    size: usize,
+    max_size: usize,
+    time_budget: Option<Duration>,
+}
+
+impl BatchSizer {
+    fn new(max_size: usize, time_budget: Option<Duration>) -> BatchSizer {
+        BatchSizer {
+            size: max_size,
+            max_size,
+            time_budget,
+        }
+    }
+
+    /// Adjust the size by the count of FSMs handled in the last round, the time
+    /// it took, and the count of FSMs still waiting to be handled.
+    fn observe(&mut self, handled: usize, elapsed: Duration, queue_depth: usize) {
+        let budget = match self.time_budget {
+            Some(budget) => budget,
+            None => return,
+        };
+        if handled == 0 {
+            return;
+        }
+        // The size that would have fit the budget, assuming every FSM takes
+        // about the same time.
+        let fit = (handled as u128 * budget.as_nanos() / elapsed.as_nanos().max(1)) as usize;
+        if elapsed > budget {
+            self.size = fit.clamp(MIN_BATCH_SIZE, self.max_size);
+        } else if handled >= self.size && queue_depth > 0 {
+            // Grow gradually, and only if the batch is full and more FSMs are
+            // waiting, a burst of cheap FSMs doesn't say much about later ones.
+            self.size = fit.min(self.size * 2).clamp(MIN_BATCH_SIZE, self.max_size);
+        }
+    }
 }
 
 //endregion
@@ -276,14 +379,18 @@ impl<N: Fsm, C: Fsm> Batch<N, C> {
 //region Poller && PollHandler
 /// A handler that poll all FSM in ready.
 pub trait PollHandler<N, C> {
-    /// This function is called at the very beginning of every round.
-    fn begin(&mut self, batch_size: usize);
+    /// This function is called at the very beginning of every round, with the
+    /// batch size chosen for the round, and the time budget of handling it if
+    /// adaptive batch sizing is enabled.
+    fn begin(&mut self, batch_size: usize, time_budget: Option<Duration>);
 
     /// This function is called when handling readiness for control FSM.
     fn handle_control(&mut self, control: &mut C) -> Option<usize>;
 
-    /// This function is called when handling readiness for normal FSM.
-    fn handle_normal(&mut self, normal: &mut N) -> Option<usize>;
+    /// This function is called when handling readiness for normal FSM. The
+    /// handler should add the count of messages it handles to
+    /// `stats.messages`, which is used by reschedule policies.
+    fn handle_normal(&mut self, normal: &mut N, stats: &mut BatchStats) -> Option<usize>;
 
     /// This function is called at the end of every round.
     fn end(&mut self, batch: &mut [Box<N>]);
@@ -293,38 +400,213 @@ pub trait PollHandler<N, C> {
     fn get_priority(&self) -> Priority { Priority::Normal }
 }
 
+impl<N, C, H: PollHandler<N, C> + ?Sized> PollHandler<N, C> for Box<H> {
+    #[inline]
+    fn begin(&mut self, batch_size: usize, time_budget: Option<Duration>) {
+        (**self).begin(batch_size, time_budget)
+    }
+
+    #[inline]
+    fn handle_control(&mut self, control: &mut C) -> Option<usize> {
+        (**self).handle_control(control)
+    }
+
+    #[inline]
+    fn handle_normal(&mut self, normal: &mut N, stats: &mut BatchStats) -> Option<usize> {
+        (**self).handle_normal(normal, stats)
+    }
+
+    #[inline]
+    fn end(&mut self, batch: &mut [Box<N>]) {
+        (**self).end(batch)
+    }
+
+    #[inline]
+    fn pause(&mut self) {
+        (**self).pause()
+    }
+
+    #[inline]
+    fn get_priority(&self) -> Priority {
+        (**self).get_priority()
+    }
+}
+
+//region StealGroup
+/// A normal FSM that is ready to be handled, with its stats in the batch.
+type ReadyFsm<N> = (Box<N>, BatchStats);
+
+/// Local queues of the pollers of one priority class, which idle pollers
+/// steal FSMs from.
+struct StealGroup<N> {
+    stealers: Mutex<Vec<(usize, Stealer<ReadyFsm<N>>)>>,
+    // It holds a single wake-up, which is taken by exactly one idle poller. If
+    // none is idle, the next one to go idle tries to steal once more.
+    signal_sender: channel::Sender<()>,
+    signal_receiver: channel::Receiver<()>,
+}
+
+impl<N> StealGroup<N> {
+    fn new() -> StealGroup<N> {
+        let (signal_sender, signal_receiver) = channel::bounded(1);
+        StealGroup {
+            stealers: Mutex::default(),
+            signal_sender,
+            signal_receiver,
+        }
+    }
+
+    fn register(&self, id: usize, stealer: Stealer<ReadyFsm<N>>) {
+        self.stealers.lock().unwrap().push((id, stealer));
+    }
+
+    fn unregister(&self, id: usize) {
+        self.stealers.lock().unwrap().retain(|(i, _)| *i != id);
+    }
+
+    /// Move about half of the FSMs from the local queue of another poller
+    /// into `local`, returns the count of stolen FSMs.
+    fn steal(&self, id: usize, local: &deque::Worker<ReadyFsm<N>>) -> usize {
+        let stealers = self.stealers.lock().unwrap().clone();
+        for (_, stealer) in stealers.iter().filter(|(i, _)| *i != id) {
+            let before = local.len();
+            loop {
+                match stealer.steal_batch(local) {
+                    Steal::Success(()) => return local.len() - before,
+                    Steal::Empty => break,
+                    Steal::Retry => {}
+                }
+            }
+        }
+        0
+    }
+}
+
+//endregion
+
 struct Poller<N: Fsm, C: Fsm, Handler> {
+    id: usize,
     router: Router<N, C, NormalScheduler<N, C>, ControlScheduler<N, C>>,
-    fsm_receiver: channel::Receiver<FsmTypes<N, C>>,
+    // The channel of the poller's own priority class comes first, followed by
+    // the ones of lower classes in weighted-fair mode.
+    fsm_receivers: Vec<channel::Receiver<FsmTypes<N, C>>>,
+    // FSMs that are fetched but not handled in the current round yet, and the
+    // ones left in the batch by previous rounds.
+    local: deque::Worker<ReadyFsm<N>>,
+    // Only set if work stealing is enabled.
+    steal_group: Option<Arc<StealGroup<N>>>,
+    // Disconnected when the poller should retire.
+    retire_receiver: channel::Receiver<()>,
+    // Disconnected when the whole system is shutting down.
+    stop_receiver: channel::Receiver<()>,
+    props: GroupProperties,
     handler: Handler,
     max_batch_size: usize,
-    reschedule_duration: Duration,
+    sizer: BatchSizer,
+    reschedule_policy: Box<dyn ReschedulePolicy>,
+    failure_policy: Arc<FailurePolicy<N>>,
+    // Recorded during a round and flushed into `shared_metrics` at the end of it.
+    metrics: PollerMetrics,
+    shared_metrics: Arc<Mutex<PollerMetrics>>,
 }
 
-enum ReschedulePolicy {
+enum RescheduleMark {
     Release(usize),
     Remove,
     Schedule,
 }
 
 impl<N: Fsm, C: Fsm, Handler: PollHandler<N, C>> Poller<N, C, Handler> {
-    fn fetch_fsm(&mut self, batch: &mut Batch<N, C>) -> bool {
-        if batch.control.is_some() {
-            return true;
-        }
+    #[inline]
+    fn is_retiring(&self) -> bool {
+        !matches!(self.retire_receiver.try_recv(), Err(TryRecvError::Empty))
+    }
 
-        if let Ok(fsm) = self.fsm_receiver.try_recv() {
-            return batch.push(fsm);
+    /// Whether FSMs of the given priority can stay in the poller's batch.
+    #[inline]
+    fn serves(&self, priority: Priority) -> bool {
+        let own = self.handler.get_priority();
+        priority == own || (self.fsm_receivers.len() > 1 && priority.is_lower_than(own))
+    }
+
+    /// Take a scheduled FSM without blocking, FSMs of lower classes are only
+    /// taken when there are none of the poller's own class.
+    fn try_fetch(&self) -> Option<FsmTypes<N, C>> {
+        self.fsm_receivers.iter().find_map(|r| r.try_recv().ok())
+    }
+
+    fn push(&self, batch: &mut Batch<N, C>, fsm: FsmTypes<N, C>) {
+        match fsm {
+            FsmTypes::Normal(n) => self.local.push((n, BatchStats::new())),
+            control => batch.push(control),
         }
+    }
+
+    fn steal(&mut self) -> bool {
+        let stolen = match self.steal_group {
+            Some(ref group) => group.steal(self.id, &self.local),
+            None => 0,
+        };
+        self.metrics.stolen += stolen as u64;
+        stolen > 0
+    }
+
+    fn fetch_fsm(&mut self, batch: &mut Batch<N, C>) -> bool {
+        loop {
+            if self.props.is_shutdown() || self.is_retiring() {
+                return false;
+            }
+
+            if batch.control.is_some() || !self.local.is_empty() {
+                return true;
+            }
+
+            if let Some(fsm) = self.try_fetch() {
+                self.push(batch, fsm);
+                return true;
+            }
+
+            if self.steal() {
+                return true;
+            }
 
-        if batch.is_empty() {
             self.handler.pause();
-            if let Ok(fsm) = self.fsm_receiver.recv() {
-                return batch.push(fsm);
+            let mut sel = channel::Select::new();
+            sel.recv(&self.retire_receiver);
+            sel.recv(&self.stop_receiver);
+            let signal = self.steal_group.as_ref().map(|g| (sel.recv(&g.signal_receiver), &g.signal_receiver));
+            let fsm_index = sel.recv(&self.fsm_receivers[0]);
+            for r in &self.fsm_receivers[1..] {
+                sel.recv(r);
+            }
+            // Only wait for readiness, FSMs are taken by `try_fetch` so that the
+            // poller's own class still comes first.
+            match sel.ready() {
+                0 | 1 => return false,
+                i if i >= fsm_index => {
+                    let fsm = match self.try_fetch() {
+                        Some(fsm) => fsm,
+                        None => match self.fsm_receivers[i - fsm_index].try_recv() {
+                            Ok(fsm) => fsm,
+                            // Taken by other pollers meanwhile.
+                            Err(TryRecvError::Empty) => continue,
+                            Err(TryRecvError::Disconnected) => return false,
+                        },
+                    };
+                    self.push(batch, fsm);
+                    return true;
+                }
+                // Another poller has FSMs to spare, try to steal them.
+                _ => {
+                    let _ = signal.unwrap().1.try_recv();
+                }
             }
         }
+    }
 
-        !batch.is_empty()
+    fn flush_metrics(&mut self) {
+        *self.shared_metrics.lock().unwrap() += self.metrics;
+        self.metrics = PollerMetrics::default();
     }
 
     /// Poll for readiness and forward to handler. Remove stale peer if necessary.
@@ -332,13 +614,28 @@ impl<N: Fsm, C: Fsm, Handler: PollHandler<N, C>> Poller<N, C, Handler> {
         let mut batch = Batch::with_capacity(self.max_batch_size);
         let mut reschedule_fsms = Vec::with_capacity(self.max_batch_size);
 
-        let mut run = true;
-        while run && self.fetch_fsm(&mut batch) {
-            let max_batch_size = std::cmp::max(self.max_batch_size, batch.normals.len());
-            self.handler.begin(max_batch_size);
+        while self.fetch_fsm(&mut batch) {
+            self.metrics.rounds += 1;
+            self.metrics.queue_depth.observe(self.fsm_receivers[0].len() as u64);
+
+            // The first FSM in the local queue is handled right away, wake up
+            // an idle poller to take the rest.
+            if let Some(ref group) = self.steal_group {
+                if self.local.len() > 1 {
+                    let _ = group.signal_sender.try_send(());
+                }
+            }
+
+            let batch_size = self.sizer.size;
+            let timer = Instant::now();
+            self.handler.begin(batch_size, self.sizer.time_budget);
+            self.metrics.begin_duration += timer.elapsed();
 
             if batch.control.is_some() {
+                let timer = Instant::now();
                 let len = self.handler.handle_control(batch.control.as_mut().unwrap());
+                self.metrics.handle_control_duration += timer.elapsed();
+                self.metrics.control_handled += 1;
                 if batch.control.as_ref().unwrap().is_stopped() {
                     batch.remove_control(&self.router.control_box);
                 } else if let Some(len) = len {
@@ -346,51 +643,112 @@ impl<N: Fsm, C: Fsm, Handler: PollHandler<N, C>> Poller<N, C, Handler> {
                 }
             }
 
-            let mut hot_fsm_count = 0;
-            for (i, p) in batch.normals.iter_mut().enumerate() {
-                let len = self.handler.handle_normal(p);
-                if p.is_stopped() {
-                    reschedule_fsms.push((i, ReschedulePolicy::Remove));
-                } else if p.get_priority() != self.handler.get_priority() {
-                    reschedule_fsms.push((i, ReschedulePolicy::Schedule));
-                } else {
-                    if batch.timers[i].elapsed() >= self.reschedule_duration {
-                        hot_fsm_count += 1;
-
-                        if hot_fsm_count % 2 == 0 {
-                            reschedule_fsms.push((i, ReschedulePolicy::Schedule));
+            // FSMs are taken from the local queue one by one, so the ones that
+            // are not handled yet can still be stolen by idle pollers.
+            self.reschedule_policy.begin();
+            let timer = Instant::now();
+            while batch.normals.len() < batch_size {
+                let (fsm, stats) = match self.local.pop() {
+                    Some(ready) => ready,
+                    None => match self.try_fetch() {
+                        Some(FsmTypes::Normal(n)) => (n, BatchStats::new()),
+                        Some(control) => {
+                            batch.push(control);
                             continue;
                         }
+                        None => break,
+                    },
+                };
+                batch.push_ready(fsm, stats);
+                let i = batch.normals.len() - 1;
+                let p = &mut batch.normals[i];
+                let stats = &mut batch.stats[i];
+                let handle_timer = Instant::now();
+                let handler = &mut self.handler;
+                // A panic only takes down the FSM that causes it, the rest of
+                // the batch is still served.
+                let len = match panic::catch_unwind(AssertUnwindSafe(|| handler.handle_normal(p, stats))) {
+                    Ok(len) => len,
+                    Err(panic) => {
+                        let fsm = batch.normals.pop().unwrap();
+                        batch.stats.pop();
+                        self.metrics.failed += 1;
+                        self.failure_policy.fail(&self.router, fsm, panic);
+                        continue;
+                    }
+                };
+                let p = &mut batch.normals[i];
+                let stats = &mut batch.stats[i];
+                stats.handle_time += handle_timer.elapsed();
+                if p.is_stopped() {
+                    reschedule_fsms.push((i, RescheduleMark::Remove));
+                } else if !self.serves(p.get_priority()) {
+                    reschedule_fsms.push((i, RescheduleMark::Schedule));
+                } else {
+                    if self.reschedule_policy.should_reschedule(stats) {
+                        reschedule_fsms.push((i, RescheduleMark::Schedule));
+                        continue;
                     }
 
                     if let Some(l) = len {
-                        reschedule_fsms.push((i, ReschedulePolicy::Release(l)));
+                        reschedule_fsms.push((i, RescheduleMark::Release(l)));
                     }
                 }
             }
-
-            let mut fsm_cnt = batch.normals.len();
-            while batch.normals.len() < max_batch_size {
-                if let Ok(fsm) = self.fsm_receiver.try_recv() {
-                    run = batch.push(fsm);
-                }
-
-                if !run || fsm_cnt >= batch.normals.len() { break; }
-
-                let len = self.handler.handle_normal(&mut batch.normals[fsm_cnt]);
-
-                if batch.normals[fsm_cnt].is_stopped() {
-                    reschedule_fsms.push((fsm_cnt, ReschedulePolicy::Remove));
-                } else if let Some(l) = len {
-                    reschedule_fsms.push((fsm_cnt, ReschedulePolicy::Release(l)));
+            let fsm_cnt = batch.normals.len();
+            let elapsed = timer.elapsed();
+            let queue_depth = self.fsm_receivers[0].len() + self.local.len();
+            self.sizer.observe(fsm_cnt, elapsed, queue_depth);
+            self.metrics.handle_normal_duration += elapsed;
+            self.metrics.normal_handled += fsm_cnt as u64;
+            self.metrics.batch_size.observe(fsm_cnt as u64);
+
+            let timer = Instant::now();
+            self.handler.end(&mut batch.normals);
+            self.metrics.end_duration += timer.elapsed();
+
+            // Because release use `swap_remove` internally, so using pop here
+            // to remove the correct FSM.
+            while let Some((r, mark)) = reschedule_fsms.pop() {
+                match mark {
+                    RescheduleMark::Release(l) => {
+                        self.metrics.released += 1;
+                        batch.release(r, l)
+                    }
+                    RescheduleMark::Remove => {
+                        self.metrics.removed += 1;
+                        batch.remove(r)
+                    }
+                    RescheduleMark::Schedule => {
+                        self.metrics.rescheduled += 1;
+                        batch.reschedule(&self.router, r)
+                    }
                 }
+            }
 
-                fsm_cnt += 1;
+            // FSMs left in the batch wait for the next round in the local queue.
+            for ready in batch.take_ready() {
+                self.local.push(ready);
             }
+            self.flush_metrics();
+        }
+        self.flush_metrics();
 
-            self.handler.end(&mut batch.normals);
+        if let Some(ref group) = self.steal_group {
+            group.unregister(self.id);
+        }
+        // A retired poller must not drop FSMs that are still in its batch.
+        if !self.props.is_shutdown() && self.is_retiring() {
+            batch.hand_back(&self.router);
+            while let Some((fsm, _)) = self.local.pop() {
+                self.router.normal_scheduler.schedule(fsm);
+            }
+        } else {
+            batch.clear();
+            while let Some((mut fsm, _)) = self.local.pop() {
+                fsm.on_drop();
+            }
         }
-        batch.clear();
     }
 }
 
@@ -403,75 +761,260 @@ pub trait HandlerBuilder<N, C> {
     fn build(&mut self, priority: Priority) -> Self::Handler;
 }
 
+type BoxedHandlerBuilder<N, C> =
+    Box<dyn FnMut(Priority) -> Box<dyn PollHandler<N, C> + Send> + Send>;
+
+type ReschedulePolicyFactory = Box<dyn Fn() -> Box<dyn ReschedulePolicy> + Send>;
+
+struct Worker {
+    priority: Priority,
+    // Dropping it tells the poller to retire.
+    retire_sender: channel::Sender<()>,
+    handle: JoinHandle<()>,
+    metrics: Arc<Mutex<PollerMetrics>>,
+}
 
 pub struct BatchSystem<N: Fsm, C: Fsm> {
     name_prefix: Option<String>,
     router: BatchRouter<N, C>,
-    receiver: channel::Receiver<FsmTypes<N, C>>,
-    low_receiver: channel::Receiver<FsmTypes<N, C>>,
-    pool_size: usize,
+    // A channel for every priority class, indexed by `Priority::index`.
+    receivers: Vec<channel::Receiver<FsmTypes<N, C>>>,
+    stop_receiver: channel::Receiver<()>,
+    props: GroupProperties,
+    pool_sizes: PerPriority<usize>,
+    weighted_fair: bool,
     max_batch_size: usize,
-    workers: Vec<JoinHandle<()>>,
-    reschedule_duration: Duration,
-    low_priority_pool_size: usize,
+    batch_time_budget: Option<Duration>,
+    handler_builder: Option<BoxedHandlerBuilder<N, C>>,
+    workers: Vec<Worker>,
+    metrics: BatchMetrics,
+    reschedule_policy: ReschedulePolicyFactory,
+    failure_policy: Arc<FailurePolicy<N>>,
+    // A group for every priority class if work stealing is enabled.
+    steal_groups: Option<Vec<Arc<StealGroup<N>>>>,
+    next_poller_id: usize,
 }
 
 impl<N, C> BatchSystem<N, C>
     where N: Fsm + Send + 'static, C: Fsm + Send + 'static {
     pub fn router(&self) -> &BatchRouter<N, C> { &self.router }
 
-    fn start_poller<B>(&mut self, name: String, priority: Priority, builder: &mut B)
-        where B: HandlerBuilder<N, C>, B::Handler: Send + 'static {
-        let handler = builder.build(priority);
-        let receiver = match priority {
-            Priority::Normal => self.receiver.clone(),
-            Priority::Low => self.low_receiver.clone()
+    /// Get a handle to the metrics registry of the system, which can be used
+    /// to take snapshots from other threads.
+    pub fn metrics_handle(&self) -> BatchMetrics { self.metrics.clone() }
+
+    /// Take a snapshot of the metrics of all pollers.
+    pub fn metrics(&self) -> MetricsSnapshot { self.metrics.snapshot() }
+
+    /// Replace the reschedule policy chosen by `Config`, every poller started
+    /// afterwards gets its own policy built by `factory`.
+    pub fn set_reschedule_policy<F>(&mut self, factory: F)
+        where F: Fn() -> Box<dyn ReschedulePolicy> + Send + 'static {
+        self.reschedule_policy = Box::new(factory);
+    }
+
+    /// Set the handler that receives normal FSMs which panic while being
+    /// handled, see `FsmFailure`. Without a handler, failures are dropped.
+    ///
+    /// A panic in `PollHandler::handle_normal` is caught by the poller, which
+    /// keeps serving the rest of its batch. Panics anywhere else still take
+    /// down the poller.
+    pub fn set_failure_handler<F>(&self, handler: F)
+        where F: Fn(FsmFailure<N>) + Send + Sync + 'static {
+        self.failure_policy.set_handler(Some(Arc::new(handler)));
+    }
+
+    pub fn clear_failure_handler(&self) {
+        self.failure_policy.set_handler(None);
+    }
+
+    /// Restart failed FSMs with `factory`, a new FSM is registered at the
+    /// address of the failed one before the failure is reported. FSMs that
+    /// the factory returns None for are not restarted.
+    pub fn set_restart_factory(&self, factory: impl FsmFactory<N> + Sync + 'static) {
+        self.failure_policy.set_restart(Some(Arc::new(factory)));
+    }
+
+    pub fn clear_restart_factory(&self) {
+        self.failure_policy.set_restart(None);
+    }
+
+    /// Start the FSMs of the supervision tree, and supervise them. Failed FSMs
+    /// in the tree are restarted by their supervisors rather than the restart
+    /// factory, and are still reported to the failure handler.
+    pub fn supervise(&self, mut supervisor: Supervisor<N>) {
+        supervisor.start(&self.router);
+        self.failure_policy.add_supervisor(supervisor);
+    }
+
+    /// Get the current number of pollers that serve the given priority.
+    pub fn pool_size(&self, priority: Priority) -> usize {
+        self.pool_sizes[priority]
+    }
+
+    fn pool_size_mut(&mut self, priority: Priority) -> &mut usize {
+        &mut self.pool_sizes[priority]
+    }
+
+    fn poller_name(&self, priority: Priority, index: usize) -> String {
+        let name_prefix = self.name_prefix.as_ref().unwrap();
+        match priority {
+            Priority::Normal => crate::thd_name!(format!("{}-{}", name_prefix, index)),
+            _ => crate::thd_name!(format!("{}-{}-{}", name_prefix, priority.as_str(), index)),
+        }
+    }
+
+    fn start_poller(&mut self, name: String, priority: Priority) {
+        let handler = (self.handler_builder.as_mut().unwrap())(priority);
+        let receivers = if self.weighted_fair {
+            self.receivers[priority.index()..].to_vec()
+        } else {
+            vec![self.receivers[priority.index()].clone()]
         };
+        let (retire_sender, retire_receiver) = channel::bounded(0);
+        let shared_metrics = self.metrics.register_poller(name.clone(), priority);
+        let id = self.next_poller_id;
+        self.next_poller_id += 1;
+        let local = deque::Worker::new_fifo();
+        let steal_group = self.steal_groups.as_ref().map(|groups| groups[priority.index()].clone());
+        if let Some(ref group) = steal_group {
+            group.register(id, local.stealer());
+        }
 
         let mut poller = Poller {
+            id,
             router: self.router.clone(),
-            fsm_receiver: receiver,
+            fsm_receivers: receivers,
+            local,
+            steal_group,
+            retire_receiver,
+            stop_receiver: self.stop_receiver.clone(),
+            props: self.props.clone(),
             handler,
             max_batch_size: self.max_batch_size,
-            reschedule_duration: self.reschedule_duration,
+            sizer: BatchSizer::new(self.max_batch_size, self.batch_time_budget),
+            reschedule_policy: (self.reschedule_policy)(),
+            failure_policy: self.failure_policy.clone(),
+            metrics: PollerMetrics::default(),
+            shared_metrics: shared_metrics.clone(),
         };
 
-        let props = util::thread_group::current_properties();
+        let props = self.props.clone();
 
         let t = thread::Builder::new()
             .name(name)
             .spawn(move || {
-                util::thread_group::set_properties(props);
+                util::thread_group::set_properties(Some(props));
                 // set_io_type(IOType::ForegroundWrite);
                 poller.poll();
             })
             .unwrap();
 
-        self.workers.push(t);
+        self.workers.push(Worker {
+            priority,
+            retire_sender,
+            handle: t,
+            metrics: shared_metrics,
+        });
     }
 
 
     pub fn spawn<B>(&mut self, name_prefix: String, mut builder: B)
-        where B: HandlerBuilder<N, C>, B::Handler: Send + 'static {
+        where B: HandlerBuilder<N, C> + Send + 'static, B::Handler: Send + 'static {
+        self.handler_builder = Some(Box::new(move |priority| {
+            Box::new(builder.build(priority)) as Box<dyn PollHandler<N, C> + Send>
+        }));
+        self.name_prefix = Some(name_prefix);
+
+        for &priority in Priority::ALL {
+            let receiver = self.receivers[priority.index()].clone();
+            self.metrics.register_queue(priority, Box::new(move || receiver.len()));
+        }
+
+        for &priority in Priority::ALL {
+            for i in 0..self.pool_size(priority) {
+                let name = self.poller_name(priority, i);
+                self.start_poller(name, priority);
+            }
+        }
+    }
 
-        for i in 0..self.pool_size{
-            self.start_poller(
-                crate::thd_name!(format!("{}-{}",name_prefix,i)),
-                Priority::Normal,
-                &mut builder
-            );
+    /// Resize the poller pool of the given priority while the system is running.
+    ///
+    /// Retired pollers hand the FSMs left in their batches back to the scheduler
+    /// before exiting, and FSMs that are waiting in the channel stay there until
+    /// a poller picks them up, so no scheduled FSM is lost. If the system is not
+    /// spawned yet, only the pool size used by `spawn` is changed.
+    ///
+    /// Metrics of retired pollers are summed into `MetricsSnapshot::retired`.
+    ///
+    /// Like `shutdown`, it panics if a retired poller panicked.
+    pub fn scale_pool(&mut self, priority: Priority, new_size: usize) {
+        let current_size = self.pool_size(priority);
+        *self.pool_size_mut(priority) = new_size;
+        if self.name_prefix.is_none() || new_size == current_size {
+            return;
         }
 
-        for i in 0..self.low_priority_pool_size{
-            self.start_poller(
-                crate::thd_name!(format!("{}-low-{}",name_prefix,i)),
-                Priority::Low,
-                &mut builder
-            );
+        if new_size > current_size {
+            for i in current_size..new_size {
+                let name = self.poller_name(priority, i);
+                self.start_poller(name, priority);
+            }
+            return;
         }
 
-        self.name_prefix=Some(name_prefix);
+        // Retire the most recently started pollers, so that the indexes in the
+        // names of the remaining ones are still continuous.
+        let mut retired = Vec::with_capacity(current_size - new_size);
+        let mut i = self.workers.len();
+        while retired.len() < current_size - new_size {
+            i -= 1;
+            if self.workers[i].priority == priority {
+                retired.push(self.workers.remove(i));
+            }
+        }
 
+        let mut last_error = None;
+        for Worker { retire_sender, handle, metrics, .. } in retired {
+            drop(retire_sender);
+            if let Err(e) = handle.join() {
+                last_error = Some(e);
+            }
+            // Names of retired pollers are reused when the pool grows again.
+            self.metrics.retire_poller(&metrics);
+        }
+        if let Some(e) = last_error {
+            panic!("failed to join retired worker thread: {:?}", e);
+        }
+    }
+
+    /// Shutdown the system after all queued messages are handled.
+    ///
+    /// New messages are rejected at once, then pollers are given up to `timeout`
+    /// to drain every mailbox. If the deadline is exceeded, it falls back to
+    /// `shutdown`, which drops whatever is left. Returns whether all mailboxes
+    /// were drained.
+    pub fn graceful_shutdown(&mut self, timeout: Duration) -> bool {
+        if self.name_prefix.is_none() {
+            return true;
+        }
+
+        self.router.close_senders();
+        let deadline = Instant::now() + timeout;
+        let drained = loop {
+            // FSMs waiting in channels are not handled yet, even if their
+            // mailboxes are empty.
+            if self.router.is_drained() && self.receivers.iter().all(|r| r.is_empty()) {
+                break true;
+            }
+            if Instant::now() >= deadline {
+                break false;
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        self.shutdown();
+        drained
     }
 
     pub fn shutdown(&mut self){
@@ -482,14 +1025,24 @@ impl<N, C> BatchSystem<N, C>
         self.router.broadcast_shutdown();
 
         let mut last_error=None;
-        for h in self.workers.drain(..){
-            println!("waiting for {}",h.thread().name().unwrap());
-            if let Err(e)=h.join(){
+        for w in self.workers.drain(..){
+            println!("waiting for {}",w.handle.thread().name().unwrap());
+            if let Err(e)=w.handle.join(){
                 println!("failed to join worker thread: {:?}",e);
                 last_error=Some(e);
             }
         }
 
+        // FSMs that are still scheduled are dropped with the system.
+        for receiver in &self.receivers{
+            while let Ok(fsm)=receiver.try_recv(){
+                match fsm {
+                    FsmTypes::Normal(mut n)=>n.on_drop(),
+                    FsmTypes::Control(mut c)=>c.on_drop(),
+                }
+            }
+        }
+
         if let Some(e)=last_error{
             panic!("failed to join worker thread: {:?}",e);
         }
@@ -512,20 +1065,22 @@ pub fn create_system<N: Fsm, C: Fsm>(
 
     let control_box=BasicMailbox::new(sender,controller,state_cnt.clone());
 
-    let (tx,rx)=channel::unbounded();
-    let(tx2,rx2)=channel::unbounded();
+    let (senders,receivers):(Vec<_>,Vec<_>)=Priority::ALL.iter().map(|_| channel::unbounded()).unzip();
+
+    let (stop,stop_rx)=StopSignal::new();
+    let props=stop.props.clone();
 
     let normal_scheduler=NormalScheduler{
-        sender:tx.clone(),
-        low_sender:tx2.clone()
+        senders:senders.clone(),
+        stop:stop.clone()
     };
 
     let control_scheduler=ControlScheduler{
-        sender:tx,
-        low_sender:tx2
+        senders,
+        stop
     };
 
-    let router=Router::new(control_box,normal_scheduler,control_scheduler,state_cnt);
+    let router=Router::new(control_box,normal_scheduler,control_scheduler,state_cnt,cfg);
 
     // let normal_box =Arc::new(Mutex::new(NormalMailMap {
     //     map: HashMap::default(),
@@ -537,14 +1092,67 @@ pub fn create_system<N: Fsm, C: Fsm>(
     let system=BatchSystem{
         name_prefix:None,
         router:router.clone(),
-        receiver:rx,
-        low_receiver:rx2,
-        pool_size:cfg.pool_size,
+        receivers,
+        stop_receiver:stop_rx,
+        props,
+        pool_sizes:cfg.pool_sizes,
+        weighted_fair:cfg.weighted_fair,
         max_batch_size:cfg.max_batch_size(),
-        reschedule_duration:cfg.reschedule_duration,
+        batch_time_budget:cfg.batch_time_budget,
+        reschedule_policy:{
+            let (policy,reschedule_duration)=(cfg.reschedule_policy,cfg.reschedule_duration);
+            Box::new(move || policy.build(reschedule_duration))
+        },
+        failure_policy:Arc::new(FailurePolicy::new()),
+        handler_builder:None,
         workers:vec![],
-        low_priority_pool_size:cfg.low_priority_pool_size
+        metrics:BatchMetrics::default(),
+        steal_groups:cfg.work_stealing.then(|| Priority::ALL.iter().map(|_| Arc::new(StealGroup::new())).collect()),
+        next_poller_id:0,
     };
     (router,system)
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_sizer() {
+        let mut sizer = BatchSizer::new(256, None);
+        sizer.observe(256, Duration::from_secs(1), 100);
+        assert_eq!(sizer.size, 256);
+
+        let budget = Duration::from_millis(10);
+        let mut sizer = BatchSizer::new(256, Some(budget));
+        assert_eq!(sizer.size, 256);
+        // Shrinks in proportion when the budget is exceeded.
+        sizer.observe(100, Duration::from_millis(40), 0);
+        assert_eq!(sizer.size, 25);
+        sizer.observe(25, Duration::from_secs(1), 0);
+        assert_eq!(sizer.size, MIN_BATCH_SIZE);
+        // Doesn't grow if the batch is not full or nothing is waiting.
+        sizer.observe(1, Duration::from_millis(1), 0);
+        assert_eq!(sizer.size, 1);
+        sizer.observe(0, Duration::from_millis(1), 10);
+        assert_eq!(sizer.size, 1);
+        // Grows at most twice every round, up to the max.
+        let mut size = 1;
+        while size < 256 {
+            sizer.observe(size, Duration::from_micros(10), 10);
+            assert_eq!(sizer.size, size * 2);
+            size *= 2;
+        }
+        sizer.observe(256, Duration::from_micros(10), 10);
+        assert_eq!(sizer.size, 256);
+        // Stays if it's within the budget.
+        sizer.observe(256, budget / 2, 0);
+        assert_eq!(sizer.size, 256);
+        sizer.observe(256, budget / 2, 10);
+        assert_eq!(sizer.size, 256);
+        sizer.observe(16, Duration::from_millis(20), 10);
+        assert_eq!(sizer.size, 8);
+        sizer.observe(8, Duration::from_millis(8), 10);
+        assert_eq!(sizer.size, 10);
+    }
+}
@@ -1,12 +1,37 @@
 use std::time::Duration;
 use serde::{Serialize, Deserialize};
+use crate::tikv_batch::fsm::{PerPriority, Priority};
+use crate::tikv_batch::reschedule::BuiltinReschedulePolicy;
 
 #[derive(Clone,Debug,Serialize,Deserialize,PartialEq)]
 pub struct Config{
     pub max_batch_size:Option<usize>,
-    pub pool_size:usize,
+    /// Enables adaptive batch sizing, the batch size of every round is tuned,
+    /// up to `max_batch_size`, so that handling a round takes about this long.
+    pub batch_time_budget:Option<Duration>,
+    /// Count of pollers of every priority class. FSMs of a class without
+    /// pollers wait until its pool is scaled, unless they are taken by pollers
+    /// of higher classes in weighted-fair mode.
+    pub pool_sizes:PerPriority<usize>,
     pub reschedule_duration:Duration,
-    pub low_priority_pool_size:usize
+    /// How pollers reschedule FSMs that keep staying in their batches, it can
+    /// be replaced by `BatchSystem::set_reschedule_policy`.
+    pub reschedule_policy:BuiltinReschedulePolicy,
+    /// Whether pollers with spare room in their batches take FSMs of lower
+    /// priority classes, the highest of them first. Otherwise every class
+    /// is only served by its own pool.
+    pub weighted_fair:bool,
+    /// Whether idle pollers steal FSMs that wait in the local queues of busy
+    /// pollers of the same priority class.
+    pub work_stealing:bool,
+    /// Count of shards the address book of the router is split into.
+    pub router_shards:usize,
+    /// Precision of timers scheduled by the router, a timer fires within
+    /// about one tick after it's due.
+    pub timer_tick:Duration,
+    /// Count of slots of the timer wheel. Timers that are due in the same
+    /// slot are checked together, so it should cover common delays in ticks.
+    pub timer_wheel_size:usize
 }
 
 impl Config{
@@ -17,11 +42,20 @@ impl Config{
 
 impl Default for Config{
     fn default() -> Self {
+        let mut pool_sizes=PerPriority::default();
+        pool_sizes[Priority::Normal]=2;
+        pool_sizes[Priority::Low]=1;
         Config{
             max_batch_size:None,
-            pool_size:2,
+            batch_time_budget:None,
+            pool_sizes,
             reschedule_duration:Duration::from_secs(5),
-            low_priority_pool_size:1
+            reschedule_policy:BuiltinReschedulePolicy::EveryOtherHot,
+            weighted_fair:false,
+            work_stealing:false,
+            router_shards:16,
+            timer_tick:Duration::from_millis(10),
+            timer_wheel_size:512
         }
     }
 }
\ No newline at end of file
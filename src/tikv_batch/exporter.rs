@@ -0,0 +1,271 @@
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use crate::tikv_batch::batch::BatchRouter;
+use crate::tikv_batch::fsm::Fsm;
+use crate::tikv_batch::metrics::{BatchMetrics, Histogram, MetricsSnapshot, PollerMetrics};
+
+/// Escape a label value, `\`, `"` and line feeds must be escaped in the text
+/// exposition format.
+fn escape_label(value: &str) -> Cow<'_, str> {
+    if !value.contains(&['\\', '"', '\n'][..]) {
+        return Cow::Borrowed(value);
+    }
+    let mut escaped = String::with_capacity(value.len() + 2);
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+fn write_header(buf: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(buf, "# HELP {} {}", name, help);
+    let _ = writeln!(buf, "# TYPE {} {}", name, kind);
+}
+
+fn write_histogram(buf: &mut String, name: &str, labels: &str, h: &Histogram) {
+    let sep = if labels.is_empty() { "" } else { "," };
+    let mut cumulative = 0;
+    for (upper_bound, cnt) in h.buckets() {
+        cumulative += cnt;
+        if upper_bound == u64::MAX {
+            let _ = writeln!(buf, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, cumulative);
+        } else {
+            let _ = writeln!(buf, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, upper_bound, cumulative);
+        }
+    }
+    let _ = writeln!(buf, "{}_sum{{{}}} {}", name, labels, h.sum());
+    let _ = writeln!(buf, "{}_count{{{}}} {}", name, labels, h.count());
+}
+
+//region Exporter
+/// Renders statistics of a batch system in the Prometheus text exposition format.
+pub struct Exporter<N: Fsm, C: Fsm> {
+    namespace: String,
+    router: BatchRouter<N, C>,
+    metrics: BatchMetrics,
+}
+
+impl<N: Fsm, C: Fsm> Exporter<N, C> {
+    /// Create an exporter, every metric name is prefixed by `namespace`.
+    pub fn new(namespace: &str, router: BatchRouter<N, C>, metrics: BatchMetrics) -> Exporter<N, C> {
+        Exporter {
+            namespace: namespace.to_owned(),
+            router,
+            metrics,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut buf = String::new();
+        self.render_router(&mut buf);
+        self.render_metrics(&mut buf, &self.metrics.snapshot());
+        buf
+    }
+
+    fn render_router(&self, buf: &mut String) {
+        let ns = &self.namespace;
+        let trace = self.router.trace();
+
+        let name = format!("{}_router_alive_mailboxes", ns);
+        write_header(buf, &name, "gauge", "Count of mailboxes registered in the router.");
+        let _ = writeln!(buf, "{} {}", name, self.router.alive_cnt().load(Ordering::Relaxed));
+
+        let name = format!("{}_router_fsm_states", ns);
+        write_header(buf, &name, "gauge", "Count of fsm states that are not destroyed.");
+        let _ = writeln!(buf, "{} {}", name, self.router.state_cnt().load(Ordering::Relaxed));
+
+        let name = format!("{}_router_memory_bytes", ns);
+        write_header(buf, &name, "gauge", "Approximate memory used by mailboxes.");
+        let _ = writeln!(buf, "{}{{type=\"alive\"}} {}", name, trace.alive);
+        let _ = writeln!(buf, "{}{{type=\"leak\"}} {}", name, trace.leak);
+
+        let name = format!("{}_mailbox_queue_wait_microseconds", ns);
+        write_header(buf, &name, "histogram", "Time messages wait in mailboxes that track latency.");
+        write_histogram(buf, &name, "", &self.router.latency());
+    }
+
+    fn render_metrics(&self, buf: &mut String, snapshot: &MetricsSnapshot) {
+        let ns = &self.namespace;
+
+        let name = format!("{}_queue_length", ns);
+        write_header(buf, &name, "gauge", "Count of fsms waiting to be polled.");
+        for (priority, len) in &snapshot.queue_lens {
+            let _ = writeln!(buf, "{}{{priority=\"{}\"}} {}", name, priority.as_str(), len);
+        }
+
+        // Retired pollers of a class are exported as a single poller, whose
+        // counters keep growing as more pollers retire.
+        let mut series: Vec<_> = snapshot.pollers.iter().map(|p| {
+            (format!("poller=\"{}\",priority=\"{}\"", escape_label(&p.name), p.priority.as_str()), &p.metrics)
+        }).collect();
+        for (priority, metrics) in snapshot.retired.iter() {
+            if *metrics != PollerMetrics::default() {
+                series.push((format!("poller=\"retired\",priority=\"{}\"", priority.as_str()), metrics));
+            }
+        }
+
+        let name = format!("{}_poller_rounds_total", ns);
+        write_header(buf, &name, "counter", "Count of polling rounds.");
+        for (labels, m) in &series {
+            let _ = writeln!(buf, "{}{{{}}} {}", name, labels, m.rounds);
+        }
+
+        let name = format!("{}_poller_fsm_handled_total", ns);
+        write_header(buf, &name, "counter", "Count of fsms handled.");
+        for (labels, m) in &series {
+            let _ = writeln!(buf, "{}{{{},type=\"control\"}} {}", name, labels, m.control_handled);
+            let _ = writeln!(buf, "{}{{{},type=\"normal\"}} {}", name, labels, m.normal_handled);
+        }
+
+        let name = format!("{}_poller_reschedule_total", ns);
+        write_header(buf, &name, "counter", "Count of fsms leaving the batch, by policy.");
+        for (labels, m) in &series {
+            let _ = writeln!(buf, "{}{{{},policy=\"release\"}} {}", name, labels, m.released);
+            let _ = writeln!(buf, "{}{{{},policy=\"remove\"}} {}", name, labels, m.removed);
+            let _ = writeln!(buf, "{}{{{},policy=\"schedule\"}} {}", name, labels, m.rescheduled);
+        }
+
+        let name = format!("{}_poller_stolen_total", ns);
+        write_header(buf, &name, "counter", "Count of fsms stolen from other pollers.");
+        for (labels, m) in &series {
+            let _ = writeln!(buf, "{}{{{}}} {}", name, labels, m.stolen);
+        }
+
+        let name = format!("{}_poller_fsm_failed_total", ns);
+        write_header(buf, &name, "counter", "Count of fsms that panicked while being handled.");
+        for (labels, m) in &series {
+            let _ = writeln!(buf, "{}{{{}}} {}", name, labels, m.failed);
+        }
+
+        let name = format!("{}_poller_duration_seconds_total", ns);
+        write_header(buf, &name, "counter", "Time spent in every stage of polling rounds.");
+        for (labels, m) in &series {
+            for (stage, d) in &[
+                ("begin", m.begin_duration),
+                ("handle_control", m.handle_control_duration),
+                ("handle_normal", m.handle_normal_duration),
+                ("end", m.end_duration),
+            ] {
+                let _ = writeln!(buf, "{}{{{},stage=\"{}\"}} {}", name, labels, stage, d.as_secs_f64());
+            }
+        }
+
+        let name = format!("{}_poller_batch_size", ns);
+        write_header(buf, &name, "histogram", "Count of normal fsms handled in a round.");
+        for (labels, m) in &series {
+            write_histogram(buf, &name, labels, &m.batch_size);
+        }
+
+        let name = format!("{}_poller_queue_depth", ns);
+        write_header(buf, &name, "histogram", "Length of the fsm channel at the beginning of a round.");
+        for (labels, m) in &series {
+            write_histogram(buf, &name, labels, &m.queue_depth);
+        }
+    }
+}
+
+impl<N, C> Exporter<N, C>
+    where N: Fsm + Send + 'static, C: Fsm + Send + 'static {
+    /// Serve `GET /metrics` on the given address from a background thread.
+    /// Failing to serve a request doesn't stop the server, the last error can
+    /// be taken by `MetricsServer::take_error`.
+    pub fn serve(self, addr: &str) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stopped_ = stopped.clone();
+        let last_error = Arc::new(Mutex::new(None));
+        let last_error_ = last_error.clone();
+        let handle = thread::Builder::new()
+            .name(crate::thd_name!("metrics-server"))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped_.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Err(e) = stream.and_then(|stream| self.handle_connection(stream)) {
+                        *last_error_.lock().unwrap() = Some(e);
+                    }
+                }
+            })?;
+
+        Ok(MetricsServer {
+            addr,
+            stopped,
+            last_error,
+            handle: Some(handle),
+        })
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(3)))?;
+        let mut buf = [0; 1024];
+        let mut n = 0;
+        // The request may arrive in pieces, closing the connection before it's
+        // fully read would reset it while the client is still writing.
+        while n < buf.len() && !buf[..n].windows(4).any(|w| w == b"\r\n\r\n") {
+            match stream.read(&mut buf[n..])? {
+                0 => break,
+                read => n += read,
+            }
+        }
+        let request = String::from_utf8_lossy(&buf[..n]);
+        let (status, body) = if request.starts_with("GET /metrics ") {
+            ("200 OK", self.render())
+        } else {
+            ("404 Not Found", String::new())
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+}
+
+//endregion
+
+//region MetricsServer
+/// A tiny HTTP server that exposes metrics, it's stopped when dropped.
+pub struct MetricsServer {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    last_error: Arc<Mutex<Option<io::Error>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    pub fn local_addr(&self) -> SocketAddr { self.addr }
+
+    /// Take the last error that happened while serving requests.
+    pub fn take_error(&self) -> Option<io::Error> {
+        self.last_error.lock().unwrap().take()
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake up the listener that is blocked on accepting.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(h) = self.handle.take() {
+            let _ = h.join();
+        }
+    }
+}
+
+//endregion
@@ -1,8 +1,10 @@
-use std::sync::atomic::{AtomicUsize, AtomicPtr, Ordering};
+use std::sync::atomic::{AtomicUsize, AtomicPtr, AtomicU64, Ordering};
 use std::sync::Arc;
 use crate::tikv_batch::mailbox::BasicMailbox;
 use std::borrow::Cow;
+use std::ops::{Index, IndexMut};
 use std::ptr;
+use serde::{Serialize, Deserialize};
 
 // The fsm is notified
 const NOTIFY_STATE_NOTIFIED:usize=0;
@@ -13,10 +15,82 @@ const NOTIFY_STATE_IDLE:usize=1;
 //The fsm is expected to be dropped.
 const NOTIFY_STATE_DROP:usize=2;
 
-#[derive(Clone,Copy,Debug,Eq, PartialEq)]
+// Lifecycle flags, so that `on_start` and `on_stop` are called only once.
+const LIFECYCLE_STARTED:usize=1;
+const LIFECYCLE_STOPPED:usize=2;
+
+// The fsm is not registered in the router.
+const NO_ADDR:u64=u64::MAX;
+
+/// Priority classes of FSMs, every class is served by a poller pool of its own.
+/// Variants are declared from the highest to the lowest, settings of classes
+/// are kept in a `PerPriority` table in the same order.
+#[derive(Clone,Copy,Debug,Eq,PartialEq,Hash)]
 pub enum Priority{
-    Low,
+    High,
     Normal,
+    Low,
+    Background,
+}
+
+impl Priority{
+    /// All classes, from the highest to the lowest.
+    pub const ALL:&'static [Priority]=&[Priority::High,Priority::Normal,Priority::Low,Priority::Background];
+
+    pub const COUNT:usize=Priority::ALL.len();
+
+    /// Position of the class in `Priority::ALL`.
+    #[inline]
+    pub fn index(self) -> usize{
+        self as usize
+    }
+
+    /// Whether the class is lower than `other`.
+    #[inline]
+    pub fn is_lower_than(self,other:Priority) -> bool{
+        self.index()>other.index()
+    }
+
+    pub fn as_str(self) -> &'static str{
+        match self{
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+            Priority::Background => "background",
+        }
+    }
+}
+
+/// A value for every priority class, indexed by `Priority`.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Serialize,Deserialize)]
+pub struct PerPriority<T>([T;Priority::COUNT]);
+
+impl<T> PerPriority<T>{
+    /// Create a table whose values are given by `f`.
+    pub fn from_fn(mut f:impl FnMut(Priority)->T) -> PerPriority<T>{
+        PerPriority(std::array::from_fn(|i| f(Priority::ALL[i])))
+    }
+
+    /// Iterate over classes and their values, from the highest class to the lowest.
+    pub fn iter(&self) -> impl Iterator<Item=(Priority,&T)>{
+        Priority::ALL.iter().copied().zip(self.0.iter())
+    }
+}
+
+impl<T> Index<Priority> for PerPriority<T>{
+    type Output=T;
+
+    #[inline]
+    fn index(&self,priority:Priority) -> &T{
+        &self.0[priority.index()]
+    }
+}
+
+impl<T> IndexMut<Priority> for PerPriority<T>{
+    #[inline]
+    fn index_mut(&mut self,priority:Priority) -> &mut T{
+        &mut self.0[priority.index()]
+    }
 }
 
 pub trait Fsm{
@@ -30,6 +104,9 @@ pub trait Fsm{
     where Self:Sized,
     {}
 
+    /// Take the mailbox set by `set_mailbox` back. A poller needs it to
+    /// release the fsm, or to close it when handling the fsm panicked, and
+    /// panics if it's gone.
     fn take_mailbox(&mut self) -> Option<BasicMailbox<Self>>
     where Self:Sized,
     {None}
@@ -37,13 +114,36 @@ pub trait Fsm{
     fn get_priority(&self) -> Priority
     {Priority::Normal}
 
+    /// Called once, when the fsm is taken to be scheduled for the first time.
+    /// It's called on the thread that schedules it, before `set_mailbox`.
+    /// That's usually a sender, inside the send path of the router, so it
+    /// should be quick. Sending through the router is fine, even to itself.
+    fn on_start(&mut self){}
+
+    /// Called when a poller releases the fsm because its mailbox is drained,
+    /// unless the fsm is stopped. An fsm that gets messages before it's
+    /// released stays in the batch, and isn't notified.
+    fn on_idle(&mut self){}
+
+    /// Called once, when a poller removes the fsm because it's stopped and its
+    /// mailbox is drained.
+    fn on_stop(&mut self){}
+
+    /// Called right before the fsm is dropped, which happens when its mailbox
+    /// is closed, or it's still in a poller or the scheduler at shutdown.
+    fn on_drop(&mut self){}
+
 }
 
+
 //region FsmState
 
-pub struct FsmState<N>{
+pub struct FsmState<N:Fsm>{
     status:AtomicUsize,
+    lifecycle:AtomicUsize,
     data:AtomicPtr<N>,
+    // The address the fsm is registered at in the router, or `NO_ADDR`.
+    addr:AtomicU64,
     state_cnt:Arc<AtomicUsize>,
 }
 
@@ -53,11 +153,27 @@ impl<N:Fsm> FsmState<N>{
         state_cnt.fetch_add(1,Ordering::Relaxed);
         FsmState{
             status:AtomicUsize::new(NOTIFY_STATE_IDLE),
+            lifecycle:AtomicUsize::new(0),
             data:AtomicPtr::new(Box::into_raw(data)),
+            addr:AtomicU64::new(NO_ADDR),
             state_cnt
         }
     }
 
+    /// The address the fsm was last registered at. It's not cleared when the
+    /// fsm is removed from the router, and an fsm registered at `u64::MAX` is
+    /// taken as not registered.
+    pub fn addr(&self) -> Option<u64>{
+        match self.addr.load(Ordering::Acquire) {
+            NO_ADDR=>None,
+            addr=>Some(addr),
+        }
+    }
+
+    pub(crate) fn set_addr(&self,addr:u64){
+        self.addr.store(addr,Ordering::Release);
+    }
+
     /// Take the fsm if it's IDLE.
     pub fn take_fsm(&self) -> Option<Box<N>>{
         let res=self.status.compare_exchange(
@@ -73,7 +189,11 @@ impl<N:Fsm> FsmState<N>{
 
         let p=self.data.swap(ptr::null_mut(),Ordering::AcqRel);
         if !p.is_null(){
-            Some(unsafe {Box::from_raw(p)})
+            let mut fsm=unsafe {Box::from_raw(p)};
+            if self.lifecycle.fetch_or(LIFECYCLE_STARTED,Ordering::AcqRel)&LIFECYCLE_STARTED==0{
+                fsm.on_start();
+            }
+            Some(fsm)
         }else{
             panic!("inconsistent status and data,something should be wrong.")
         }
@@ -94,6 +214,21 @@ impl<N:Fsm> FsmState<N>{
         }
     }
 
+    /// Mark the fsm stopped, `Fsm::on_stop` is called if it's not stopped yet.
+    /// A stopped fsm is not notified by `on_idle` when it's released.
+    pub fn stop(&self,fsm:&mut N){
+        if self.lifecycle.fetch_or(LIFECYCLE_STOPPED,Ordering::AcqRel)&LIFECYCLE_STOPPED==0{
+            fsm.on_stop();
+        }
+    }
+
+    /// Call `Fsm::on_idle` unless the fsm is stopped, right before it's released.
+    pub fn idle(&self,fsm:&mut N){
+        if self.lifecycle.load(Ordering::Acquire)&LIFECYCLE_STOPPED==0{
+            fsm.on_idle();
+        }
+    }
+
     #[inline]
     pub fn release(&self,fsm:Box<N>){
         let previous=self.data.swap(Box::into_raw(fsm),Ordering::AcqRel);
@@ -112,7 +247,7 @@ impl<N:Fsm> FsmState<N>{
                 Ok(_) => return,
                 Err(NOTIFY_STATE_DROP) =>{
                     let ptr=self.data.swap(ptr::null_mut(),Ordering::AcqRel);
-                    unsafe{Box::from_raw(ptr)};
+                    unsafe{Box::from_raw(ptr)}.on_drop();
                     return;
                 }
                 Err(s) => s,
@@ -131,18 +266,18 @@ impl<N:Fsm> FsmState<N>{
 
         let ptr=self.data.swap(ptr::null_mut(),Ordering::SeqCst);
         if !ptr.is_null(){
-            unsafe {Box::from_raw(ptr);}
+            unsafe {Box::from_raw(ptr)}.on_drop();
         }
     }
 
 
 }
 
-impl<N> Drop for FsmState<N>{
+impl<N:Fsm> Drop for FsmState<N>{
     fn drop(&mut self) {
         let ptr=self.data.swap(ptr::null_mut(),Ordering::SeqCst);
         if !ptr.is_null(){
-            unsafe {Box::from_raw(ptr)};
+            unsafe {Box::from_raw(ptr)}.on_drop();
         }
 
         self.state_cnt.fetch_sub(1,Ordering::Relaxed);
@@ -1,11 +1,16 @@
 use crate::tikv_batch::fsm::{Fsm, FsmState, FsmScheduler};
 use std::sync::Arc;
-use crate::tikv_batch::mpsc::LooseBoundedSender;
+use crate::tikv_batch::mpsc::{BoundPolicy, LooseBoundedSender};
 use std::sync::atomic::AtomicUsize;
 use crossbeam::channel::{
     self, RecvError, RecvTimeoutError, SendError, TryRecvError, TrySendError,
 };
 use std::borrow::Cow;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use crate::tikv_batch::metrics::Histogram;
+use crate::tikv_batch::ask::{self, AskHandle, Reply};
 
 //region BasicMailbox
 pub struct BasicMailbox<Owner: Fsm> {
@@ -37,6 +42,31 @@ impl<Owner: Fsm> BasicMailbox<Owner> {
         self.state.take_fsm()
     }
 
+    /// Whether both are the mailbox of the same fsm.
+    #[inline]
+    pub(crate) fn ptr_eq(&self, other: &BasicMailbox<Owner>) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+
+    /// The address the fsm was last registered at, see `FsmState::addr`.
+    #[inline]
+    pub(crate) fn addr(&self) -> Option<u64> {
+        self.state.addr()
+    }
+
+    #[inline]
+    pub(crate) fn set_addr(&self, addr: u64) {
+        self.state.set_addr(addr)
+    }
+
+    pub(crate) fn stop(&self, fsm: &mut Owner) {
+        self.state.stop(fsm)
+    }
+
+    pub(crate) fn idle(&self, fsm: &mut Owner) {
+        self.state.idle(fsm)
+    }
+
     #[inline]
     pub fn len(&self) -> usize {
         self.sender.len()
@@ -47,6 +77,24 @@ impl<Owner: Fsm> BasicMailbox<Owner> {
         self.sender.is_empty()
     }
 
+    /// The capacity limit of the mailbox, see `policy` for how it's enforced.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.sender.limit()
+    }
+
+    #[inline]
+    pub fn policy(&self) -> BoundPolicy {
+        self.sender.policy()
+    }
+
+    /// Get the histogram of the time messages wait in the mailbox, in microseconds.
+    /// It's only available if the mailbox is created by `loose_bounded_with_latency`.
+    #[inline]
+    pub fn latency(&self) -> Option<Histogram> {
+        self.sender.latency()
+    }
+
 
     #[inline]
     pub fn force_send<S: FsmScheduler<Fsm=Owner>>(
@@ -70,6 +118,77 @@ impl<Owner: Fsm> BasicMailbox<Owner> {
         Ok(())
     }
 
+    /// Same as `try_send`, but it may wait for room, see
+    /// `LooseBoundedSender::send_blocking`.
+    pub fn send_blocking<S: FsmScheduler<Fsm=Owner>>(
+        &self,
+        msg: Owner::Message,
+        scheduler: &S,
+    ) -> Result<(), TrySendError<Owner::Message>> {
+        self.sender.send_blocking(msg)?;
+        self.state.notify(scheduler, Cow::Borrowed(self));
+        Ok(())
+    }
+
+    /// Send messages from `msgs` until it's exhausted or a message can't be
+    /// sent, the fsm is notified only once for all of them. Returns the count
+    /// of sent messages, the message that can't be sent is given back in the
+    /// error and the rest are left in `msgs`.
+    pub fn try_send_many<S, I>(
+        &self,
+        msgs: &mut I,
+        scheduler: &S,
+    ) -> (usize, Result<(), TrySendError<Owner::Message>>)
+        where S: FsmScheduler<Fsm=Owner>, I: Iterator<Item=Owner::Message> {
+        let mut sent = 0;
+        let mut res = Ok(());
+        for msg in msgs {
+            if let Err(e) = self.sender.try_send(msg) {
+                res = Err(e);
+                break;
+            }
+            sent += 1;
+        }
+        if sent > 0 {
+            self.state.notify(scheduler, Cow::Borrowed(self));
+        }
+        (sent, res)
+    }
+
+    /// Try to send the message in `msg`. If the mailbox is full, the message is
+    /// put back and the task is woken up once the mailbox may have room.
+    pub fn poll_send<S: FsmScheduler<Fsm=Owner>>(
+        &self,
+        cx: &mut Context<'_>,
+        msg: &mut Option<Owner::Message>,
+        scheduler: &S,
+    ) -> Poll<Result<(), SendError<Owner::Message>>> {
+        let m = match self.try_send(msg.take().unwrap(), scheduler) {
+            Ok(()) => return Poll::Ready(Ok(())),
+            Err(TrySendError::Disconnected(m)) => return Poll::Ready(Err(SendError(m))),
+            Err(TrySendError::Full(m)) => m,
+        };
+
+        self.sender.register_waker(cx);
+        // Messages may be received before the waker is registered, try again
+        // to avoid missing the wake up.
+        match self.try_send(m, scheduler) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(TrySendError::Disconnected(m)) => Poll::Ready(Err(SendError(m))),
+            Err(TrySendError::Full(m)) => {
+                *msg = Some(m);
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Stop accepting new messages. Unlike `close`, messages that are already
+    /// queued are kept and the fsm can still be scheduled to handle them.
+    #[inline]
+    pub(crate) fn close_sender(&self) {
+        self.sender.close_sender();
+    }
+
     #[inline]
     pub(crate) fn close(&self) {
         self.sender.close_sender();
@@ -116,6 +235,88 @@ impl<Owner, Scheduler> Mailbox<Owner, Scheduler>
         self.mailbox.try_send(msg,&self.scheduler)
     }
 
+    /// See `BasicMailbox::send_blocking`.
+    #[inline]
+    pub fn send_blocking(&self,msg:Owner::Message) -> Result<(),TrySendError<Owner::Message>>{
+        self.mailbox.send_blocking(msg,&self.scheduler)
+    }
+
+    /// See `BasicMailbox::try_send_many`.
+    #[inline]
+    pub fn try_send_many<I>(&self,msgs:&mut I) -> (usize,Result<(),TrySendError<Owner::Message>>)
+        where I:Iterator<Item=Owner::Message>{
+        self.mailbox.try_send_many(msgs,&self.scheduler)
+    }
+
+    /// Send a message built with a reply channel, and return the handle to
+    /// wait for the response.
+    pub fn ask<R,F>(&self,build_msg:F) -> Result<AskHandle<R>,TrySendError<Owner::Message>>
+        where F:FnOnce(Reply<R>)->Owner::Message{
+        let (reply,handle)=ask::reply_channel();
+        self.try_send(build_msg(reply))?;
+        Ok(handle)
+    }
+
+}
+
+impl<Owner, Scheduler> Mailbox<Owner, Scheduler>
+    where Owner: Fsm, Scheduler: FsmScheduler<Fsm=Owner> + Clone {
+
+    /// Send a message, waiting for capacity if the mailbox is full.
+    ///
+    /// The returned future owns a clone of the mailbox, it fails if the mailbox
+    /// is closed before the message can be sent.
+    pub fn send_async(&self,msg:Owner::Message) -> SendFuture<Owner,Scheduler>{
+        SendFuture::new(Some(self.clone()),msg)
+    }
+}
+
+impl<Owner, Scheduler> Clone for Mailbox<Owner, Scheduler>
+    where Owner: Fsm, Scheduler: FsmScheduler<Fsm=Owner> + Clone {
+    #[inline]
+    fn clone(&self) -> Self {
+        Mailbox{
+            mailbox:self.mailbox.clone(),
+            scheduler:self.scheduler.clone(),
+        }
+    }
+}
+
+//endregion
+
+//region SendFuture
+/// Future returned by `send_async`, which resolves once the message is sent.
+pub struct SendFuture<Owner, Scheduler>
+    where
+        Owner: Fsm,
+        Scheduler: FsmScheduler<Fsm=Owner> {
+    // None if the target mailbox doesn't exist.
+    mailbox: Option<Mailbox<Owner, Scheduler>>,
+    msg: Option<Owner::Message>,
+}
+
+impl<Owner, Scheduler> SendFuture<Owner, Scheduler>
+    where Owner: Fsm, Scheduler: FsmScheduler<Fsm=Owner> {
+    pub(crate) fn new(mailbox:Option<Mailbox<Owner,Scheduler>>,msg:Owner::Message) -> SendFuture<Owner,Scheduler>{
+        SendFuture{mailbox,msg:Some(msg)}
+    }
+}
+
+// Fields are never pinned.
+impl<Owner, Scheduler> Unpin for SendFuture<Owner, Scheduler>
+    where Owner: Fsm, Scheduler: FsmScheduler<Fsm=Owner> {}
+
+impl<Owner, Scheduler> Future for SendFuture<Owner, Scheduler>
+    where Owner: Fsm, Scheduler: FsmScheduler<Fsm=Owner> {
+    type Output = Result<(), SendError<Owner::Message>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match this.mailbox {
+            Some(ref mailbox) => mailbox.mailbox.poll_send(cx, &mut this.msg, &mailbox.scheduler),
+            None => Poll::Ready(Err(SendError(this.msg.take().unwrap()))),
+        }
+    }
 }
 
 //endregion
\ No newline at end of file
@@ -0,0 +1,270 @@
+use std::ops::{Add, AddAssign};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use derive_more::{Add, AddAssign};
+use crate::tikv_batch::fsm::{PerPriority, Priority};
+
+const HISTOGRAM_BUCKETS: usize = 32;
+
+//region Histogram
+/// A histogram with power-of-two buckets.
+///
+/// Bucket `i` counts the observed values in `[2^(i-1), 2^i)`, and bucket 0
+/// counts zeros. Values that don't fit are put into the last bucket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Histogram {
+    buckets: [u64; HISTOGRAM_BUCKETS],
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Histogram {
+    #[inline]
+    pub fn observe(&mut self, value: u64) {
+        let idx = (64 - value.leading_zeros() as usize).min(HISTOGRAM_BUCKETS - 1);
+        self.buckets[idx] += 1;
+        self.count += 1;
+        self.sum += value;
+        self.max = self.max.max(value);
+    }
+
+    #[inline]
+    pub fn count(&self) -> u64 { self.count }
+
+    #[inline]
+    pub fn sum(&self) -> u64 { self.sum }
+
+    #[inline]
+    pub fn max(&self) -> u64 { self.max }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+
+    /// Get the upper bound of the bucket that contains the given quantile,
+    /// which is never larger than the max observed value.
+    pub fn quantile(&self, q: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let target = ((self.count as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (upper_bound, cnt) in self.buckets() {
+            seen += cnt;
+            if seen >= target {
+                return upper_bound.min(self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Iterate over `(inclusive upper bound, count)` of every bucket.
+    pub fn buckets(&self) -> impl Iterator<Item=(u64, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(i, cnt)| {
+            let upper_bound = if i == HISTOGRAM_BUCKETS - 1 {
+                u64::MAX
+            } else {
+                (1u64 << i) - 1
+            };
+            (upper_bound, *cnt)
+        })
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: [0; HISTOGRAM_BUCKETS],
+            count: 0,
+            sum: 0,
+            max: 0,
+        }
+    }
+}
+
+impl AddAssign for Histogram {
+    fn add_assign(&mut self, rhs: Histogram) {
+        for (l, r) in self.buckets.iter_mut().zip(rhs.buckets.iter()) {
+            *l += r;
+        }
+        self.count += rhs.count;
+        self.sum += rhs.sum;
+        self.max = self.max.max(rhs.max);
+    }
+}
+
+impl Add for Histogram {
+    type Output = Histogram;
+
+    fn add(mut self, rhs: Histogram) -> Histogram {
+        self += rhs;
+        self
+    }
+}
+
+//endregion
+
+//region PollerMetrics
+/// Counters recorded by a single poller.
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, PartialEq)]
+pub struct PollerMetrics {
+    /// Count of polling rounds.
+    pub rounds: u64,
+    /// Count of normal fsms in the batch of every round.
+    pub batch_size: Histogram,
+    /// Length of the fsm channel observed at the beginning of every round.
+    pub queue_depth: Histogram,
+    pub control_handled: u64,
+    pub normal_handled: u64,
+    /// Count of fsms released back to their mailboxes.
+    pub released: u64,
+    /// Count of stopped fsms removed from the batch.
+    pub removed: u64,
+    /// Count of fsms sent back to the scheduler.
+    pub rescheduled: u64,
+    /// Count of fsms stolen from the local queues of other pollers.
+    pub stolen: u64,
+    /// Count of fsms that panicked while being handled.
+    pub failed: u64,
+    pub begin_duration: Duration,
+    pub handle_control_duration: Duration,
+    pub handle_normal_duration: Duration,
+    pub end_duration: Duration,
+}
+
+//endregion
+
+//region BatchMetrics
+struct PollerEntry {
+    name: String,
+    priority: Priority,
+    metrics: Arc<Mutex<PollerMetrics>>,
+}
+
+type QueueProbe = Box<dyn Fn() -> usize + Send + Sync>;
+
+#[derive(Default)]
+struct BatchMetricsInner {
+    pollers: Mutex<Vec<PollerEntry>>,
+    // Metrics of retired pollers, summed by the class they served.
+    retired: Mutex<PerPriority<PollerMetrics>>,
+    queues: Mutex<Vec<(Priority, QueueProbe)>>,
+}
+
+/// Registry of metrics of all pollers in a batch system.
+///
+/// Every poller flushes its local counters into its own slot at the end of
+/// a round, the registry only aggregates them when a snapshot is taken.
+#[derive(Clone, Default)]
+pub struct BatchMetrics {
+    inner: Arc<BatchMetricsInner>,
+}
+
+impl BatchMetrics {
+    pub(crate) fn register_poller(&self, name: String, priority: Priority) -> Arc<Mutex<PollerMetrics>> {
+        let metrics = Arc::new(Mutex::new(PollerMetrics::default()));
+        self.inner.pollers.lock().unwrap().push(PollerEntry {
+            name,
+            priority,
+            metrics: metrics.clone(),
+        });
+        metrics
+    }
+
+    /// Remove the poller that owns `metrics`, its metrics are added to the
+    /// retired total of its class.
+    pub(crate) fn retire_poller(&self, metrics: &Arc<Mutex<PollerMetrics>>) {
+        let mut pollers = self.inner.pollers.lock().unwrap();
+        if let Some(i) = pollers.iter().position(|p| Arc::ptr_eq(&p.metrics, metrics)) {
+            let p = pollers.remove(i);
+            self.inner.retired.lock().unwrap()[p.priority] += *p.metrics.lock().unwrap();
+        }
+    }
+
+    pub(crate) fn register_queue(&self, priority: Priority, probe: QueueProbe) {
+        self.inner.queues.lock().unwrap().push((priority, probe));
+    }
+
+    /// Take a snapshot of the metrics, including those of retired pollers.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let mut total = PollerMetrics::default();
+        let pollers = self.inner.pollers.lock().unwrap();
+        let retired = *self.inner.retired.lock().unwrap();
+        for (_, metrics) in retired.iter() {
+            total += *metrics;
+        }
+        let pollers: Vec<_> = pollers.iter().map(|p| {
+            let metrics = *p.metrics.lock().unwrap();
+            total += metrics;
+            PollerSnapshot {
+                name: p.name.clone(),
+                priority: p.priority,
+                metrics,
+            }
+        }).collect();
+        let queue_lens = self.inner.queues.lock().unwrap()
+            .iter()
+            .map(|(priority, probe)| (*priority, probe()))
+            .collect();
+
+        MetricsSnapshot {
+            pollers,
+            retired,
+            total,
+            queue_lens,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PollerSnapshot {
+    pub name: String,
+    pub priority: Priority,
+    pub metrics: PollerMetrics,
+}
+
+#[derive(Clone, Debug)]
+pub struct MetricsSnapshot {
+    /// Pollers that are not retired.
+    pub pollers: Vec<PollerSnapshot>,
+    /// Sum of the metrics of retired pollers, by the class they served.
+    pub retired: PerPriority<PollerMetrics>,
+    /// Sum of the metrics of all pollers, including retired ones.
+    pub total: PollerMetrics,
+    /// Current length of the fsm channel of every priority.
+    pub queue_lens: Vec<(Priority, usize)>,
+}
+
+//endregion
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut h = Histogram::default();
+        assert_eq!(h.quantile(0.99), 0);
+        for v in 1..=100 {
+            h.observe(v);
+        }
+        assert_eq!(h.count(), 100);
+        assert_eq!(h.sum(), 5050);
+        assert_eq!(h.max(), 100);
+        assert_eq!(h.quantile(0.5), 63);
+        assert_eq!(h.quantile(0.99), 100);
+
+        let mut other = Histogram::default();
+        other.observe(0);
+        other.observe(1000);
+        h += other;
+        assert_eq!(h.count(), 102);
+        assert_eq!(h.max(), 1000);
+        assert_eq!(h.buckets().next(), Some((0, 1)));
+    }
+}
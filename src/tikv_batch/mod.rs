@@ -1,12 +1,23 @@
+pub mod ask;
 pub mod batch;
+pub mod exporter;
 pub mod fsm;
 pub mod mailbox;
+pub mod metrics;
 pub mod mpsc;
+pub mod reschedule;
 pub mod router;
+pub mod supervisor;
+pub mod timer;
 pub mod util;
 pub mod config;
-pub mod test_runner;
-pub mod test_batch;
-pub mod test_route;
+#[cfg(test)]
+mod test_runner;
+#[cfg(test)]
+mod test_batch;
+#[cfg(test)]
+mod test_route;
+#[cfg(test)]
+mod test_exporter;
 
 
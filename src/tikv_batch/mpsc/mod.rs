@@ -1,19 +1,78 @@
-use std::cell::Cell;
 use std::sync::Arc;
 use crossbeam::channel;
 use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering, AtomicIsize};
 use crossbeam::channel::{
     RecvError, RecvTimeoutError, SendError, TryRecvError, TrySendError,
 };
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::task::{Context, Waker};
+use std::thread::{self, Thread};
+use futures::task::{self as futures_task, ArcWake};
+use crate::tikv_batch::metrics::Histogram;
 
 const CHECK_INTERVAL: usize = 8;
 
+/// How a bounded sender enforces its capacity limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BoundPolicy {
+    /// The length is only checked every `check_interval` sends, so the limit
+    /// may be exceeded by up to `check_interval` messages of every sender.
+    Loose { check_interval: usize },
+    /// `try_send` never exceeds the limit.
+    Strict,
+    /// The oldest message is dropped to make room for the new one, so sending
+    /// never fails because of the limit.
+    DropOldest,
+    /// `send_blocking` blocks the current thread until there is room or the
+    /// timeout elapses. `try_send` never blocks, it behaves like `Strict`.
+    Block { timeout: Duration },
+}
+
+impl Default for BoundPolicy {
+    fn default() -> BoundPolicy {
+        BoundPolicy::Loose { check_interval: CHECK_INTERVAL }
+    }
+}
+
+/// Lanes of a channel created by `loose_bounded_with_lanes`. Every lane is
+/// a queue of its own, so an urgent message isn't stuck behind bulk ones.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Lane {
+    Urgent,
+    Normal,
+    Background,
+}
+
+impl Lane {
+    const COUNT: usize = 3;
+}
+
+/// Wakes up a thread that is blocked on sending.
+struct ThreadWaker(Thread);
+
+impl ArcWake for ThreadWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.unpark();
+    }
+}
+
 //region LooseBoundedSender
+/// A sender with a capacity limit, which is enforced by its `BoundPolicy`.
+/// The limit is loose by default, see `loose_bounded`.
+///
+/// `force_send` never fails because of the limit. It ignores the limit
+/// except for `BoundPolicy::DropOldest`, which drops the oldest message instead.
 pub struct LooseBoundedSender<T> {
     sender: Sender<T>,
-    tried_cnt: Cell<usize>,
+    // An atomic is used instead of `Cell`, so that the sender can be shared
+    // between threads. The count is only a hint, hence `Relaxed` is enough.
+    tried_cnt: AtomicUsize,
     limit: usize,
+    policy: BoundPolicy,
+    // Used to drop the oldest message, only set for `BoundPolicy::DropOldest`.
+    evictor: Option<channel::Receiver<T>>,
 }
 
 impl<T> LooseBoundedSender<T> {
@@ -28,20 +87,70 @@ impl<T> LooseBoundedSender<T> {
     }
 
     #[inline]
-    pub fn force_send(&self, t: T) -> Result<(), SendError<T>> {
-        let cnt = self.tried_cnt.get();
-        self.tried_cnt.set(cnt + 1);
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
 
-        self.sender.send(t)
+    #[inline]
+    pub fn policy(&self) -> BoundPolicy {
+        self.policy
+    }
+
+    #[inline]
+    pub fn force_send(&self, t: T) -> Result<(), SendError<T>> {
+        match self.policy {
+            BoundPolicy::Loose { .. } => {
+                self.tried_cnt.fetch_add(1, Ordering::Relaxed);
+                self.sender.send(t)
+            }
+            BoundPolicy::DropOldest => self.send_drop_oldest(t),
+            BoundPolicy::Strict | BoundPolicy::Block { .. } => {
+                // Still counted, so that `try_send` sees the real length.
+                self.sender.state.reserve(usize::MAX);
+                self.send_reserved(t).map_err(|e| SendError(e.into_inner()))
+            }
+        }
     }
 
     #[inline]
     pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
-        let cnt = self.tried_cnt.get();
-        if cnt < CHECK_INTERVAL {
-            self.tried_cnt.set(cnt + 1);
+        match self.policy {
+            BoundPolicy::Loose { check_interval } => self.try_send_loose(t, check_interval),
+            BoundPolicy::Strict | BoundPolicy::Block { .. } => self.try_send_strict(t),
+            BoundPolicy::DropOldest => self.send_drop_oldest(t).map_err(|SendError(t)| TrySendError::Disconnected(t)),
+        }
+    }
+
+    /// Same as `try_send`, but if the policy is `BoundPolicy::Block`, it waits
+    /// for room until the timeout elapses. It must not be called with locks
+    /// held, or from pollers and async tasks.
+    pub fn send_blocking(&self, t: T) -> Result<(), TrySendError<T>> {
+        match self.policy {
+            BoundPolicy::Block { timeout } => self.send_timeout(t, timeout),
+            _ => self.try_send(t),
+        }
+    }
+
+    #[inline]
+    fn full_or_disconnected(&self, t: T) -> TrySendError<T> {
+        // A closed channel should never be reported as full.
+        if self.is_sender_connected() {
+            TrySendError::Full(t)
+        } else {
+            TrySendError::Disconnected(t)
+        }
+    }
+
+    #[inline]
+    fn try_send_loose(&self, t: T, check_interval: usize) -> Result<(), TrySendError<T>> {
+        let cnt = self.tried_cnt.load(Ordering::Relaxed);
+        if cnt < check_interval {
+            self.tried_cnt.store(cnt + 1, Ordering::Relaxed);
         } else if self.len() < self.limit {
-            self.tried_cnt.set(1);
+            self.tried_cnt.store(1, Ordering::Relaxed);
+        } else if !self.is_sender_connected() {
+            // A closed channel should never be reported as full.
+            return Err(TrySendError::Disconnected(t));
         } else {
             return Err(TrySendError::Full(t));
         }
@@ -52,6 +161,65 @@ impl<T> LooseBoundedSender<T> {
         }
     }
 
+    /// Send a message whose room is already reserved.
+    #[inline]
+    fn send_reserved(&self, t: T) -> Result<(), TrySendError<T>> {
+        match self.sender.send(t) {
+            Ok(()) => Ok(()),
+            Err(SendError(t)) => {
+                self.sender.state.unreserve();
+                Err(TrySendError::Disconnected(t))
+            }
+        }
+    }
+
+    #[inline]
+    fn try_send_strict(&self, t: T) -> Result<(), TrySendError<T>> {
+        if !self.sender.state.reserve(self.limit) {
+            return Err(self.full_or_disconnected(t));
+        }
+        self.send_reserved(t)
+    }
+
+    fn send_drop_oldest(&self, mut t: T) -> Result<(), SendError<T>> {
+        let evictor = self.evictor.as_ref().unwrap();
+        loop {
+            match self.try_send_strict(t) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(t)) => return Err(SendError(t)),
+                Err(TrySendError::Full(m)) => t = m,
+            }
+            // The channel may be empty if the room is reserved by other
+            // senders that haven't sent yet, then just try again.
+            if evictor.try_recv().is_ok() {
+                self.sender.state.on_received();
+            }
+        }
+    }
+
+    fn send_timeout(&self, mut t: T, timeout: Duration) -> Result<(), TrySendError<T>> {
+        let deadline = Instant::now() + timeout;
+        let waker = futures_task::waker(Arc::new(ThreadWaker(thread::current())));
+        loop {
+            match self.try_send_strict(t) {
+                Err(TrySendError::Full(m)) => t = m,
+                res => return res,
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(TrySendError::Full(t));
+            }
+            self.sender.state.register_waker(&waker);
+            // Messages may be received before the waker is registered, try
+            // again to avoid missing the wake up.
+            match self.try_send_strict(t) {
+                Err(TrySendError::Full(m)) => t = m,
+                res => return res,
+            }
+            thread::park_timeout(deadline - now);
+        }
+    }
+
     #[inline]
     pub fn close_sender(&self) {
         self.sender.close_sender();
@@ -61,6 +229,19 @@ impl<T> LooseBoundedSender<T> {
     pub fn is_sender_connected(&self) -> bool {
         self.sender.state.is_sender_connected()
     }
+
+    /// Register a waker that is woken up when a message is received or the
+    /// channel is disconnected, so that a full sender can try again.
+    #[inline]
+    pub fn register_waker(&self, cx: &Context<'_>) {
+        self.sender.state.register_waker(cx.waker());
+    }
+
+    /// Get the histogram of queue wait time, see `loose_bounded_with_latency`.
+    #[inline]
+    pub fn latency(&self) -> Option<Histogram> {
+        self.sender.state.latency.as_ref().map(LatencyTracker::histogram)
+    }
 }
 
 impl<T> Clone for LooseBoundedSender<T> {
@@ -68,8 +249,10 @@ impl<T> Clone for LooseBoundedSender<T> {
     fn clone(&self) -> Self {
         LooseBoundedSender {
             sender: self.sender.clone(),
-            tried_cnt: self.tried_cnt.clone(),
+            tried_cnt: AtomicUsize::new(self.tried_cnt.load(Ordering::Relaxed)),
             limit: self.limit,
+            policy: self.policy,
+            evictor: self.evictor.clone(),
         }
     }
 }
@@ -79,14 +262,24 @@ impl<T> Clone for LooseBoundedSender<T> {
 
 #[inline]
 pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
-    let state = Arc::new(State::new());
+    unbounded_with_state(State::new())
+}
+
+#[inline]
+fn unbounded_with_state<T>(state: State) -> (Sender<T>, Receiver<T>) {
+    let state = Arc::new(state);
     let (sender, receiver) = channel::unbounded();
     (
         Sender {
             sender,
+            lanes: None,
             state: state.clone(),
         },
-        Receiver { receiver, state },
+        Receiver {
+            receiver,
+            lanes: None,
+            state,
+        },
     )
 }
 
@@ -97,29 +290,157 @@ pub fn bounded<T>(cap: usize) -> (Sender<T>, Receiver<T>) {
     (
         Sender {
             sender,
+            lanes: None,
             state: state.clone(),
         },
-        Receiver { receiver, state },
+        Receiver {
+            receiver,
+            lanes: None,
+            state,
+        },
     )
 }
 
 pub fn loose_bounded<T>(cap: usize) -> (LooseBoundedSender<T>, Receiver<T>) {
-    let (sender, receiver) = unbounded();
+    loose_bounded_with_state(cap, State::new())
+}
+
+/// Same as `loose_bounded`, but the length is checked every `check_interval` sends.
+pub fn loose_bounded_with_interval<T>(cap: usize, check_interval: usize) -> (LooseBoundedSender<T>, Receiver<T>) {
+    bounded_with_policy(cap, BoundPolicy::Loose { check_interval })
+}
+
+/// Create a channel whose capacity limit is never exceeded by `try_send`.
+pub fn strict_bounded<T>(cap: usize) -> (LooseBoundedSender<T>, Receiver<T>) {
+    bounded_with_policy(cap, BoundPolicy::Strict)
+}
+
+/// Create a channel that drops the oldest message when it's full.
+pub fn drop_oldest_bounded<T>(cap: usize) -> (LooseBoundedSender<T>, Receiver<T>) {
+    bounded_with_policy(cap, BoundPolicy::DropOldest)
+}
+
+/// Create a channel whose senders wait up to `timeout` for room when it's full,
+/// see `LooseBoundedSender::send_blocking`.
+pub fn blocking_bounded<T>(cap: usize, timeout: Duration) -> (LooseBoundedSender<T>, Receiver<T>) {
+    bounded_with_policy(cap, BoundPolicy::Block { timeout })
+}
+
+pub fn bounded_with_policy<T>(cap: usize, policy: BoundPolicy) -> (LooseBoundedSender<T>, Receiver<T>) {
+    let mut state = State::new();
+    // The loose policy relies on the length of the channel, others count
+    // messages by themselves to check the limit exactly.
+    state.counted = !matches!(policy, BoundPolicy::Loose { .. });
+    let (mut sender, receiver) = loose_bounded_with_state(cap, state);
+    sender.policy = policy;
+    if policy == BoundPolicy::DropOldest {
+        sender.evictor = Some(receiver.receiver.clone());
+    }
+    (sender, receiver)
+}
+
+/// Same as `loose_bounded`, but every message is timestamped when it's sent,
+/// and the time it waits in the channel is recorded when it's received.
+pub fn loose_bounded_with_latency<T>(cap: usize) -> (LooseBoundedSender<T>, Receiver<T>) {
+    let mut state = State::new();
+    state.latency = Some(LatencyTracker::default());
+    loose_bounded_with_state(cap, state)
+}
+
+/// Same as `loose_bounded`, but messages are put into the lane chosen by
+/// `classify`. The receiver takes up to `weights[i]` messages from lane `i`,
+/// in the order of `Lane`, before it moves on to the next lane, and starts
+/// over from the urgent lane once every lane is empty or out of turns. So
+/// urgent messages are received first, and no lane is starved.
+///
+/// The limit applies to all lanes together. Latency is not tracked, as
+/// messages don't leave the channel in the order they are sent.
+pub fn loose_bounded_with_lanes<T>(
+    cap: usize,
+    weights: [usize; Lane::COUNT],
+    classify: fn(&T) -> Lane,
+) -> (LooseBoundedSender<T>, Receiver<T>) {
+    assert!(weights.iter().all(|w| *w > 0), "weights of lanes must be positive");
+    let (mut sender, mut receiver) = loose_bounded(cap);
+    let (urgent_tx, urgent_rx) = channel::unbounded();
+    let (background_tx, background_rx) = channel::unbounded();
+    sender.sender.lanes = Some(Arc::new(SenderLanes {
+        urgent: urgent_tx,
+        background: background_tx,
+        classify,
+    }));
+    receiver.lanes = Some(ReceiverLanes {
+        urgent: urgent_rx,
+        background: background_rx,
+        weights,
+        credits: Default::default(),
+    });
+    (sender, receiver)
+}
+
+fn loose_bounded_with_state<T>(cap: usize, state: State) -> (LooseBoundedSender<T>, Receiver<T>) {
+    let (sender, receiver) = unbounded_with_state(state);
     (
         LooseBoundedSender {
             sender,
-            tried_cnt: Cell::new(0),
+            tried_cnt: AtomicUsize::new(0),
             limit: cap,
+            policy: BoundPolicy::default(),
+            evictor: None,
         },
         receiver,
     )
 }
 
+//region LatencyTracker
+/// Records the time messages wait in a channel, in microseconds.
+#[derive(Default)]
+struct LatencyTracker {
+    // Send time of messages that are still in the channel, in the same order.
+    enqueued: Mutex<VecDeque<Instant>>,
+    histogram: Mutex<Histogram>,
+}
+
+impl LatencyTracker {
+    /// Send a message and record its send time. The lock is held during sending,
+    /// so that timestamps are kept in the same order as messages.
+    #[inline]
+    fn send<E>(&self, send: impl FnOnce() -> Result<(), E>) -> Result<(), E> {
+        let mut enqueued = self.enqueued.lock().unwrap();
+        send()?;
+        enqueued.push_back(Instant::now());
+        Ok(())
+    }
+
+    #[inline]
+    fn on_received(&self) {
+        let sent = self.enqueued.lock().unwrap().pop_front();
+        if let Some(sent) = sent {
+            let elapsed = sent.elapsed().as_micros() as u64;
+            self.histogram.lock().unwrap().observe(elapsed);
+        }
+    }
+
+    fn histogram(&self) -> Histogram {
+        *self.histogram.lock().unwrap()
+    }
+}
+
+//endregion
+
 
 //region State
 pub struct State {
     sender_cnt: AtomicIsize,
     connected: AtomicBool,
+    latency: Option<LatencyTracker>,
+    // Count of messages that are sent or being sent and not received yet.
+    // It's only maintained if `counted` is true.
+    counted: bool,
+    len: AtomicUsize,
+    // Senders waiting for capacity.
+    waker_cnt: AtomicUsize,
+    wakers: Mutex<Vec<Waker>>,
 }
 
 impl State {
@@ -127,19 +448,86 @@ impl State {
         State {
             sender_cnt: AtomicIsize::new(1),
             connected: AtomicBool::new(true),
+            latency: None,
+            counted: false,
+            len: AtomicUsize::new(0),
+            waker_cnt: AtomicUsize::new(0),
+            wakers: Mutex::new(vec![]),
         }
     }
 
     #[inline]
     fn is_sender_connected(&self) -> bool { self.connected.load(Ordering::Acquire) }
+
+    /// Reserve room for a message, fails if there are `limit` messages already.
+    #[inline]
+    fn reserve(&self, limit: usize) -> bool {
+        self.len
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |len| if len < limit { Some(len + 1) } else { None })
+            .is_ok()
+    }
+
+    #[inline]
+    fn unreserve(&self) {
+        self.len.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// Called whenever a message is taken out of the channel.
+    #[inline]
+    fn on_received(&self) {
+        if let Some(tracker) = &self.latency {
+            tracker.on_received();
+        }
+        if self.counted {
+            self.unreserve();
+        }
+        self.wake_senders();
+    }
+
+    fn register_waker(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock().unwrap();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+            self.waker_cnt.store(wakers.len(), Ordering::Release);
+        }
+    }
+
+    #[inline]
+    fn wake_senders(&self) {
+        if self.waker_cnt.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        let wakers = {
+            let mut wakers = self.wakers.lock().unwrap();
+            self.waker_cnt.store(0, Ordering::Release);
+            std::mem::take(&mut *wakers)
+        };
+        for w in wakers {
+            w.wake();
+        }
+    }
+
+    #[inline]
+    fn disconnect(&self) {
+        self.connected.store(false, Ordering::Release);
+        self.wake_senders();
+    }
 }
 
 //endregion
 
 
 //region Sender
+struct SenderLanes<T> {
+    urgent: channel::Sender<T>,
+    background: channel::Sender<T>,
+    classify: fn(&T) -> Lane,
+}
+
 pub struct Sender<T> {
+    // The normal lane, it's the only one if `lanes` is `None`.
     sender: channel::Sender<T>,
+    lanes: Option<Arc<SenderLanes<T>>>,
     state: Arc<State>,
 }
 
@@ -149,6 +537,7 @@ impl<T> Clone for Sender<T> {
         self.state.sender_cnt.fetch_add(1, Ordering::AcqRel);
         Sender {
             sender: self.sender.clone(),
+            lanes: self.lanes.clone(),
             state: self.state.clone(),
         }
     }
@@ -166,16 +555,41 @@ impl<T> Drop for Sender<T> {
 
 impl<T> Sender<T> {
     #[inline]
-    pub fn len(&self) -> usize { self.sender.len() }
+    pub fn len(&self) -> usize {
+        match self.lanes {
+            Some(ref l) => self.sender.len() + l.urgent.len() + l.background.len(),
+            None => self.sender.len(),
+        }
+    }
 
     #[inline]
-    pub fn is_empty(&self) -> bool { self.sender.is_empty() }
+    pub fn is_empty(&self) -> bool {
+        match self.lanes {
+            Some(ref l) => self.sender.is_empty() && l.urgent.is_empty() && l.background.is_empty(),
+            None => self.sender.is_empty(),
+        }
+    }
+
+    #[inline]
+    fn lane_sender(&self, t: &T) -> &channel::Sender<T> {
+        match self.lanes {
+            Some(ref l) => match (l.classify)(t) {
+                Lane::Urgent => &l.urgent,
+                Lane::Normal => &self.sender,
+                Lane::Background => &l.background,
+            },
+            None => &self.sender,
+        }
+    }
 
     /// Blocks current thread until a message is sent or the channel is disconnected.
     #[inline]
     pub fn send(&self, t: T) -> Result<(), SendError<T>> {
         if self.state.is_sender_connected() {
-            self.sender.send(t)
+            match self.state.latency {
+                Some(ref tracker) => tracker.send(|| self.sender.send(t)),
+                None => self.lane_sender(&t).send(t),
+            }
         } else {
             Err(SendError(t))
         }
@@ -186,7 +600,10 @@ impl<T> Sender<T> {
     #[inline]
     pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
         if self.state.is_sender_connected() {
-            self.sender.try_send(t)
+            match self.state.latency {
+                Some(ref tracker) => tracker.send(|| self.sender.try_send(t)),
+                None => self.lane_sender(&t).try_send(t),
+            }
         } else {
             Err(TrySendError::Disconnected(t))
         }
@@ -195,7 +612,7 @@ impl<T> Sender<T> {
     /// Set state to disconnected , stop sending any message.
     #[inline]
     pub fn close_sender(&self) {
-        self.state.connected.store(false, Ordering::Release);
+        self.state.disconnect();
     }
 
     #[inline]
@@ -208,47 +625,265 @@ impl<T> Sender<T> {
 //endregion
 
 //region Receiver
+struct ReceiverLanes<T> {
+    urgent: channel::Receiver<T>,
+    background: channel::Receiver<T>,
+    weights: [usize; Lane::COUNT],
+    // Messages taken from every lane in the current turn. There is only one
+    // receiver, atomics are used just to keep it `Sync`.
+    credits: [AtomicUsize; Lane::COUNT],
+}
+
+impl<T> ReceiverLanes<T> {
+    fn try_recv(&self, normal: &channel::Receiver<T>) -> Result<T, TryRecvError> {
+        let lanes = [&self.urgent, normal, &self.background];
+        let mut disconnected = 0;
+        for _ in 0..2 {
+            for (i, lane) in lanes.iter().enumerate() {
+                if self.credits[i].load(Ordering::Relaxed) >= self.weights[i] {
+                    continue;
+                }
+                match lane.try_recv() {
+                    Ok(t) => {
+                        self.credits[i].fetch_add(1, Ordering::Relaxed);
+                        return Ok(t);
+                    }
+                    Err(TryRecvError::Disconnected) => disconnected += 1,
+                    Err(TryRecvError::Empty) => {}
+                }
+            }
+            // Every lane is either empty or out of turns, start a new turn.
+            for c in &self.credits {
+                c.store(0, Ordering::Relaxed);
+            }
+        }
+        // All lanes are tried in the second turn, and their senders are
+        // dropped together.
+        if disconnected >= Lane::COUNT {
+            Err(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    /// Waits until any lane is ready, returns false on timeout.
+    fn wait(&self, normal: &channel::Receiver<T>, deadline: Option<Instant>) -> bool {
+        let mut sel = channel::Select::new();
+        sel.recv(&self.urgent);
+        sel.recv(normal);
+        sel.recv(&self.background);
+        match deadline {
+            Some(d) => sel.ready_deadline(d).is_ok(),
+            None => {
+                sel.ready();
+                true
+            }
+        }
+    }
+}
+
 pub struct Receiver<T> {
+    // The normal lane, it's the only one if `lanes` is `None`.
     receiver: channel::Receiver<T>,
+    lanes: Option<ReceiverLanes<T>>,
     state: Arc<State>,
 }
 
 impl<T> Receiver<T> {
     #[inline]
     pub fn len(&self) -> usize {
-        self.receiver.len()
+        match self.lanes {
+            Some(ref l) => self.receiver.len() + l.urgent.len() + l.background.len(),
+            None => self.receiver.len(),
+        }
     }
 
     #[inline]
     pub fn is_empty(&self) -> bool {
-        self.receiver.is_empty()
+        self.len() == 0
+    }
+
+    #[inline]
+    fn on_received<E>(&self, res: Result<T, E>) -> Result<T, E> {
+        if res.is_ok() {
+            self.state.on_received();
+        }
+        res
     }
 
     /// blocking receive message
     #[inline]
     pub fn recv(&self) -> Result<T, RecvError> {
-        self.receiver.recv()
+        let lanes = match self.lanes {
+            Some(ref l) => l,
+            None => return self.on_received(self.receiver.recv()),
+        };
+        loop {
+            match lanes.try_recv(&self.receiver) {
+                Ok(t) => return self.on_received(Ok(t)),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => lanes.wait(&self.receiver, None),
+            };
+        }
     }
 
     /// receive message without blocking
     #[inline]
     pub fn try_recv(&self) -> Result<T, TryRecvError> {
-        self.receiver.try_recv()
+        match self.lanes {
+            Some(ref l) => self.on_received(l.try_recv(&self.receiver)),
+            None => self.on_received(self.receiver.try_recv()),
+        }
     }
 
     /// receive message with timeout
     #[inline]
     pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
-        self.receiver.recv_timeout(timeout)
+        let lanes = match self.lanes {
+            Some(ref l) => l,
+            None => return self.on_received(self.receiver.recv_timeout(timeout)),
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            match lanes.try_recv(&self.receiver) {
+                Ok(t) => return self.on_received(Ok(t)),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {
+                    if !lanes.wait(&self.receiver, Some(deadline)) {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                }
+            }
+        }
     }
 }
 
 impl<T> Drop for Receiver<T> {
     #[inline]
     fn drop(&mut self) {
-        self.state.connected.store(false, Ordering::Release);
+        self.state.disconnect();
     }
 }
 
 
-//endregion
\ No newline at end of file
+//endregion
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loose_bounded() {
+        let (tx, rx) = loose_bounded_with_interval(1, 2);
+        assert_eq!(tx.try_send(1), Ok(()));
+        assert_eq!(tx.try_send(2), Ok(()));
+        assert_eq!(tx.try_send(3), Err(TrySendError::Full(3)));
+        assert_eq!(tx.force_send(4), Ok(()));
+        assert_eq!(rx.len(), 3);
+
+        let (tx, _rx) = loose_bounded(1);
+        assert_eq!((0..).take_while(|i| tx.try_send(*i).is_ok()).count(), CHECK_INTERVAL);
+    }
+
+    #[test]
+    fn test_strict_bounded() {
+        let (tx, rx) = strict_bounded(2);
+        assert_eq!(tx.policy(), BoundPolicy::Strict);
+        assert_eq!(tx.try_send(1), Ok(()));
+        assert_eq!(tx.clone().try_send(2), Ok(()));
+        assert_eq!(tx.try_send(3), Err(TrySendError::Full(3)));
+        // Forced messages are counted as well.
+        assert_eq!(tx.force_send(4), Ok(()));
+        assert_eq!(rx.recv(), Ok(1));
+        assert_eq!(tx.try_send(5), Err(TrySendError::Full(5)));
+        assert_eq!(rx.recv(), Ok(2));
+        assert_eq!(tx.try_send(6), Ok(()));
+
+        drop(rx);
+        assert_eq!(tx.try_send(7), Err(TrySendError::Disconnected(7)));
+    }
+
+    #[test]
+    fn test_drop_oldest_bounded() {
+        let (tx, rx) = drop_oldest_bounded(2);
+        for i in 0..5 {
+            assert_eq!(tx.try_send(i), Ok(()));
+        }
+        assert_eq!(tx.force_send(5), Ok(()));
+        assert_eq!(rx.len(), 2);
+        assert_eq!(rx.recv(), Ok(4));
+        assert_eq!(rx.recv(), Ok(5));
+
+        tx.close_sender();
+        assert_eq!(tx.try_send(6), Err(TrySendError::Disconnected(6)));
+    }
+
+    #[test]
+    fn test_blocking_bounded() {
+        let (tx, _rx) = blocking_bounded(1, Duration::from_millis(50));
+        assert_eq!(tx.try_send(1), Ok(()));
+        // Only `send_blocking` waits for room.
+        let timer = Instant::now();
+        assert_eq!(tx.try_send(2), Err(TrySendError::Full(2)));
+        assert!(timer.elapsed() < Duration::from_millis(50));
+        assert_eq!(tx.send_blocking(2), Err(TrySendError::Full(2)));
+        assert!(timer.elapsed() >= Duration::from_millis(50));
+
+        let (tx, rx) = blocking_bounded(1, Duration::from_secs(3));
+        let rx = Arc::new(rx);
+        assert_eq!(tx.send_blocking(1), Ok(()));
+        let rx_ = rx.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            rx_.recv().unwrap()
+        });
+        assert_eq!(tx.send_blocking(2), Ok(()));
+        assert_eq!(handle.join().unwrap(), 1);
+
+        // A blocked sender should be woken up once the channel is closed.
+        let handle = thread::spawn(move || tx.send_blocking(3));
+        thread::sleep(Duration::from_millis(20));
+        drop(rx);
+        assert_eq!(handle.join().unwrap(), Err(TrySendError::Disconnected(3)));
+    }
+
+    #[test]
+    fn test_lanes() {
+        fn classify(t: &(Lane, usize)) -> Lane { t.0 }
+        let (tx, rx) = loose_bounded_with_lanes(100, [2, 2, 1], classify);
+        for i in 0..4 {
+            tx.try_send((Lane::Background, i)).unwrap();
+            tx.try_send((Lane::Normal, i)).unwrap();
+            tx.try_send((Lane::Urgent, i)).unwrap();
+        }
+        assert_eq!(tx.len(), 12);
+        let received: Vec<_> = (0..12).map(|_| rx.try_recv().unwrap()).collect();
+        // Lanes take turns by weight, and every lane is FIFO.
+        assert_eq!(received, vec![
+            (Lane::Urgent, 0), (Lane::Urgent, 1), (Lane::Normal, 0), (Lane::Normal, 1), (Lane::Background, 0),
+            (Lane::Urgent, 2), (Lane::Urgent, 3), (Lane::Normal, 2), (Lane::Normal, 3), (Lane::Background, 1),
+            (Lane::Background, 2), (Lane::Background, 3),
+        ]);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        // An urgent message skips the queued ones, as long as the urgent lane
+        // has turns left.
+        tx.try_send((Lane::Background, 4)).unwrap();
+        tx.try_send((Lane::Background, 5)).unwrap();
+        assert_eq!(rx.try_recv(), Ok((Lane::Background, 4)));
+        tx.try_send((Lane::Urgent, 4)).unwrap();
+        assert_eq!(rx.recv(), Ok((Lane::Urgent, 4)));
+        assert_eq!(rx.recv_timeout(Duration::from_millis(10)), Ok((Lane::Background, 5)));
+        assert_eq!(rx.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+
+        let rx = Arc::new(rx);
+        let rx_ = rx.clone();
+        let handle = thread::spawn(move || rx_.recv());
+        thread::sleep(Duration::from_millis(20));
+        tx.try_send((Lane::Background, 6)).unwrap();
+        assert_eq!(handle.join().unwrap(), Ok((Lane::Background, 6)));
+
+        drop(tx);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(rx.recv(), Err(RecvError));
+    }
+}
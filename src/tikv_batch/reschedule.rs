@@ -0,0 +1,171 @@
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+
+/// What a poller knows about a normal FSM since it joined the batch.
+#[derive(Clone, Copy, Debug)]
+pub struct BatchStats {
+    /// When the FSM joined the batch.
+    pub since: Instant,
+    /// Count of messages handled, as added by `PollHandler::handle_normal`.
+    pub messages: usize,
+    /// Time spent in `PollHandler::handle_normal` for the FSM.
+    pub handle_time: Duration,
+}
+
+impl BatchStats {
+    pub fn new() -> BatchStats {
+        BatchStats {
+            since: Instant::now(),
+            messages: 0,
+            handle_time: Duration::default(),
+        }
+    }
+}
+
+impl Default for BatchStats {
+    fn default() -> BatchStats {
+        BatchStats::new()
+    }
+}
+
+/// Decides whether a normal FSM that keeps staying in the batch of a poller
+/// should be sent back to the scheduler, so that a busy FSM doesn't starve
+/// the others in the same batch.
+///
+/// Every poller owns its policy, so a policy can keep state across rounds.
+pub trait ReschedulePolicy: Send {
+    /// Called at the beginning of every round.
+    fn begin(&mut self) {}
+
+    /// Called after an FSM is handled, if it would stay in the batch otherwise.
+    fn should_reschedule(&mut self, stats: &BatchStats) -> bool;
+}
+
+/// Never reschedules, FSMs stay in the batch as long as they are busy.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NeverReschedule;
+
+impl ReschedulePolicy for NeverReschedule {
+    fn should_reschedule(&mut self, _: &BatchStats) -> bool {
+        false
+    }
+}
+
+/// An FSM is hot once it stays in the batch for `reschedule_duration`, every
+/// second hot FSM in a round is rescheduled.
+#[derive(Clone, Copy, Debug)]
+pub struct EveryOtherHot {
+    reschedule_duration: Duration,
+    hot_fsm_count: usize,
+}
+
+impl EveryOtherHot {
+    pub fn new(reschedule_duration: Duration) -> EveryOtherHot {
+        EveryOtherHot {
+            reschedule_duration,
+            hot_fsm_count: 0,
+        }
+    }
+}
+
+impl ReschedulePolicy for EveryOtherHot {
+    fn begin(&mut self) {
+        self.hot_fsm_count = 0;
+    }
+
+    fn should_reschedule(&mut self, stats: &BatchStats) -> bool {
+        if stats.since.elapsed() < self.reschedule_duration {
+            return false;
+        }
+        self.hot_fsm_count += 1;
+        self.hot_fsm_count & 1 == 0
+    }
+}
+
+/// Reschedules an FSM once it has handled `budget` messages in the batch.
+/// Handlers that don't report handled messages are never rescheduled.
+#[derive(Clone, Copy, Debug)]
+pub struct MessageBudget {
+    pub budget: usize,
+}
+
+impl ReschedulePolicy for MessageBudget {
+    fn should_reschedule(&mut self, stats: &BatchStats) -> bool {
+        stats.messages >= self.budget
+    }
+}
+
+/// Reschedules an FSM once handling it in the batch has taken `budget`.
+/// The time is measured around `PollHandler::handle_normal`, so it's the CPU
+/// time only if the handler doesn't block.
+#[derive(Clone, Copy, Debug)]
+pub struct CpuTimeBudget {
+    pub budget: Duration,
+}
+
+impl ReschedulePolicy for CpuTimeBudget {
+    fn should_reschedule(&mut self, stats: &BatchStats) -> bool {
+        stats.handle_time >= self.budget
+    }
+}
+
+/// Built-in reschedule policies that can be chosen by `Config`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum BuiltinReschedulePolicy {
+    Never,
+    /// See `EveryOtherHot`, it uses `Config::reschedule_duration`.
+    EveryOtherHot,
+    MessageBudget(usize),
+    CpuTimeBudget(Duration),
+}
+
+impl BuiltinReschedulePolicy {
+    pub fn build(self, reschedule_duration: Duration) -> Box<dyn ReschedulePolicy> {
+        match self {
+            BuiltinReschedulePolicy::Never => Box::new(NeverReschedule),
+            BuiltinReschedulePolicy::EveryOtherHot => Box::new(EveryOtherHot::new(reschedule_duration)),
+            BuiltinReschedulePolicy::MessageBudget(budget) => Box::new(MessageBudget { budget }),
+            BuiltinReschedulePolicy::CpuTimeBudget(budget) => Box::new(CpuTimeBudget { budget }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(age: Duration, messages: usize, handle_time: Duration) -> BatchStats {
+        BatchStats {
+            since: Instant::now() - age,
+            messages,
+            handle_time,
+        }
+    }
+
+    #[test]
+    fn test_builtin_policies() {
+        let hot = stats(Duration::from_secs(10), 100, Duration::from_secs(1));
+        let cold = stats(Duration::default(), 0, Duration::default());
+
+        let mut policy = BuiltinReschedulePolicy::Never.build(Duration::default());
+        assert!(!policy.should_reschedule(&hot));
+
+        let mut policy = BuiltinReschedulePolicy::EveryOtherHot.build(Duration::from_secs(5));
+        policy.begin();
+        let marks: Vec<_> = (0..4).map(|_| policy.should_reschedule(&hot)).collect();
+        assert_eq!(marks, vec![false, true, false, true]);
+        assert!(!policy.should_reschedule(&cold));
+        // The count starts over in every round.
+        policy.should_reschedule(&hot);
+        policy.begin();
+        assert!(!policy.should_reschedule(&hot));
+
+        let mut policy = BuiltinReschedulePolicy::MessageBudget(100).build(Duration::default());
+        assert!(policy.should_reschedule(&hot));
+        assert!(!policy.should_reschedule(&stats(Duration::default(), 99, Duration::default())));
+
+        let mut policy = BuiltinReschedulePolicy::CpuTimeBudget(Duration::from_secs(1)).build(Duration::default());
+        assert!(policy.should_reschedule(&hot));
+        assert!(!policy.should_reschedule(&cold));
+    }
+}
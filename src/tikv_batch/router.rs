@@ -1,13 +1,20 @@
 use crate::tikv_batch::fsm::{Fsm, FsmScheduler, FsmState};
-use std::sync::{Mutex, Arc};
+use std::sync::{Mutex, Arc, Weak};
 use std::collections::HashMap;
-use crate::tikv_batch::mailbox::{BasicMailbox, Mailbox};
+use crate::tikv_batch::mailbox::{BasicMailbox, Mailbox, SendFuture};
+use crate::tikv_batch::mpsc::LooseBoundedSender;
 use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
-use std::cell::Cell;
+use std::sync::MutexGuard;
 use crate::tikv_batch::util::lru::LruCache;
 use crate::tikv_batch::util::Either;
 use crossbeam::channel::{SendError, TrySendError};
 use std::mem;
+use std::time::Duration;
+use crate::tikv_batch::metrics::Histogram;
+use crate::tikv_batch::ask::{self, AskHandle, Reply};
+use std::fmt;
+use crate::tikv_batch::config::Config;
+use crate::tikv_batch::timer::{Timer, TimerHandle};
 
 /// A struct that traces the approximate memory usage of router.
 #[derive(Default)]
@@ -16,10 +23,316 @@ pub struct RouterTrace {
     pub leak: usize,
 }
 
+/// A factory that creates fsms for addresses without registered mailboxes.
+pub trait FsmFactory<N: Fsm>: Send {
+    /// Create the fsm of given address and the sender of its mailbox.
+    /// Returns None if no fsm should be created for the address.
+    fn create(&self, addr: u64) -> Option<(LooseBoundedSender<N::Message>, Box<N>)>;
+}
+
+impl<N, F> FsmFactory<N> for F
+    where N: Fsm,
+          F: Fn(u64) -> Option<(LooseBoundedSender<N::Message>, Box<N>)> + Send {
+    #[inline]
+    fn create(&self, addr: u64) -> Option<(LooseBoundedSender<N::Message>, Box<N>)> {
+        self(addr)
+    }
+}
+
+type MailMapShard<N> = HashMap<u64, BasicMailbox<N>>;
+
+/// The address book of normal mailboxes.
+///
+/// It's split into shards by address, every shard has its own lock, so that
+/// operations on different addresses rarely contend with each other.
 struct NormalMailMap<N: Fsm> {
-    map: HashMap<u64, BasicMailbox<N>>,
-    //Count of Mailboxes that is stored in `map`.
+    shards: Vec<Mutex<MailMapShard<N>>>,
+    //Count of Mailboxes that is stored in `shards`.
     alive_cnt: Arc<AtomicUsize>,
+    factory: Mutex<Option<Box<dyn FsmFactory<N>>>>,
+    // Set once the router is drained, no mailbox can be added afterwards.
+    draining: AtomicBool,
+}
+
+impl<N: Fsm> NormalMailMap<N> {
+    fn new(shard_cnt: usize) -> NormalMailMap<N> {
+        NormalMailMap {
+            shards: (0..shard_cnt.max(1)).map(|_| Mutex::default()).collect(),
+            alive_cnt: Arc::default(),
+            factory: Mutex::new(None),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    #[inline]
+    fn shard_index(&self, addr: u64) -> usize {
+        addr as usize % self.shards.len()
+    }
+
+    #[inline]
+    fn shard(&self, addr: u64) -> MutexGuard<'_, MailMapShard<N>> {
+        self.shards[self.shard_index(addr)].lock().unwrap()
+    }
+
+    /// Lock the shards of all given addresses, the guard of shard `i` is at
+    /// index `i`. Shards are always locked in ascending order, so that two
+    /// callers can never deadlock.
+    fn lock_shards(&self, addrs: impl Iterator<Item=u64>) -> Vec<Option<MutexGuard<'_, MailMapShard<N>>>> {
+        let mut needed = vec![false; self.shards.len()];
+        for addr in addrs {
+            needed[self.shard_index(addr)] = true;
+        }
+        self.shards.iter().zip(needed)
+            .map(|(shard, needed)| if needed { Some(shard.lock().unwrap()) } else { None })
+            .collect()
+    }
+
+    /// Insert the mailbox into the locked shard, returns the replaced one. If
+    /// the map is draining, the mailbox is rejected and returned instead.
+    ///
+    /// The flag is checked with the shard locked, so a mailbox inserted
+    /// concurrently with `close_senders` is either rejected or closed by it.
+    #[inline]
+    fn insert_in(&self, shard: &mut MailMapShard<N>, addr: u64, mailbox: BasicMailbox<N>) -> Option<BasicMailbox<N>> {
+        if self.draining.load(Ordering::SeqCst) {
+            return Some(mailbox);
+        }
+        mailbox.set_addr(addr);
+        let replaced = shard.insert(addr, mailbox);
+        if replaced.is_none() {
+            self.alive_cnt.fetch_add(1, Ordering::Relaxed);
+        }
+        replaced
+    }
+
+    fn insert(&self, addr: u64, mailbox: BasicMailbox<N>) -> Option<BasicMailbox<N>> {
+        self.insert_in(&mut self.shard(addr), addr, mailbox)
+    }
+
+    #[inline]
+    fn remove_in(&self, shard: &mut MailMapShard<N>, addr: u64) -> Option<BasicMailbox<N>> {
+        let removed = shard.remove(&addr);
+        if removed.is_some() {
+            self.alive_cnt.fetch_sub(1, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    fn remove(&self, addr: u64) -> Option<BasicMailbox<N>> {
+        self.remove_in(&mut self.shard(addr), addr)
+    }
+
+    /// Insert all mailboxes, returns the replaced ones, whose senders are closed.
+    ///
+    /// If `atomic` is true, all involved shards are locked during the whole
+    /// operation, so that a sender sees either none or all of the changes.
+    fn insert_all(&self, mailboxes: Vec<(u64, BasicMailbox<N>)>, atomic: bool) -> Vec<(u64, BasicMailbox<N>)> {
+        let mut replaced = vec![];
+        if !atomic {
+            for (addr, mailbox) in mailboxes {
+                if let Some(m) = self.insert(addr, mailbox) {
+                    m.close_sender();
+                    replaced.push((addr, m));
+                }
+            }
+            return replaced;
+        }
+
+        let mut shards = self.lock_shards(mailboxes.iter().map(|(addr, _)| *addr));
+        for (addr, mailbox) in mailboxes {
+            let shard = shards[self.shard_index(addr)].as_mut().unwrap();
+            if let Some(m) = self.insert_in(shard, addr, mailbox) {
+                // Close it before unlocking, so that no sender can reach it
+                // through caches once the new group is visible.
+                m.close_sender();
+                replaced.push((addr, m));
+            }
+        }
+        replaced
+    }
+
+    /// Remove mailboxes of all given addresses, returns the removed ones, whose
+    /// senders are closed. See `insert_all` for `atomic`.
+    fn remove_all(&self, addrs: &[u64], atomic: bool) -> Vec<(u64, BasicMailbox<N>)> {
+        let mut removed = vec![];
+        if !atomic {
+            for addr in addrs {
+                if let Some(m) = self.remove(*addr) {
+                    m.close_sender();
+                    removed.push((*addr, m));
+                }
+            }
+            return removed;
+        }
+
+        let mut shards = self.lock_shards(addrs.iter().copied());
+        for addr in addrs {
+            let shard = shards[self.shard_index(*addr)].as_mut().unwrap();
+            if let Some(m) = self.remove_in(shard, *addr) {
+                m.close_sender();
+                removed.push((*addr, m));
+            }
+        }
+        removed
+    }
+
+    /// Create a mailbox with the factory and register it with given address.
+    ///
+    /// It's called with the shard of the address locked, so concurrent senders
+    /// of the same address can never create the fsm twice.
+    fn create(&self, shard: &mut MailMapShard<N>, addr: u64, state_cnt: &Arc<AtomicUsize>) -> Option<BasicMailbox<N>> {
+        if self.draining.load(Ordering::SeqCst) {
+            return None;
+        }
+        let (sender, fsm) = self.factory.lock().unwrap().as_ref()?.create(addr)?;
+        let mailbox = BasicMailbox::new(sender, fsm, state_cnt.clone());
+        self.insert_in(shard, addr, mailbox.clone());
+        Some(mailbox)
+    }
+
+    /// Call `f` on every mailbox. Shards are locked one by one, so it's not
+    /// an atomic view of the whole address book.
+    fn for_each(&self, mut f: impl FnMut(u64, &BasicMailbox<N>)) {
+        for shard in &self.shards {
+            for (addr, mailbox) in shard.lock().unwrap().iter() {
+                f(*addr, mailbox);
+            }
+        }
+    }
+
+    /// Clone mailboxes whose addresses match `pred`.
+    fn snapshot(&self, mut pred: impl FnMut(u64) -> bool) -> Vec<(u64, BasicMailbox<N>)> {
+        let mut mailboxes = vec![];
+        self.for_each(|addr, mailbox| {
+            if pred(addr) {
+                mailboxes.push((addr, mailbox.clone()));
+            }
+        });
+        mailboxes
+    }
+
+    /// Remove all mailboxes and call `f` on each of them.
+    fn drain(&self, mut f: impl FnMut(u64, BasicMailbox<N>)) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            self.alive_cnt.fetch_sub(shard.len(), Ordering::Relaxed);
+            for (addr, mailbox) in shard.drain() {
+                f(addr, mailbox);
+            }
+        }
+    }
+}
+
+/// The reason why a message can't be delivered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeadLetterReason {
+    /// No mailbox is registered with the address.
+    NotExist,
+    /// The mailbox is closed.
+    Disconnected,
+    /// The mailbox is full.
+    Full,
+}
+
+impl fmt::Display for DeadLetterReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeadLetterReason::NotExist => write!(f, "mailbox not exist"),
+            DeadLetterReason::Disconnected => write!(f, "mailbox disconnected"),
+            DeadLetterReason::Full => write!(f, "mailbox full"),
+        }
+    }
+}
+
+/// A sink that receives undeliverable messages with their target address.
+pub type DeadLetterSink<M> = Arc<dyn Fn(u64, M, DeadLetterReason) + Send + Sync>;
+
+/// The count of sent messages, and the failed message as `Router::try_send`
+/// gives back.
+pub type SendManyResult<M> = (usize, Either<Result<(), TrySendError<M>>, M>);
+
+/// How a broadcast accesses the address book.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BroadcastMode {
+    /// Messages are generated and sent with the shard of the target locked.
+    Locked,
+    /// Matched mailboxes are cloned first, then messages are generated and
+    /// sent without holding any lock, so that a slow generator never blocks
+    /// other senders. Mailboxes closed meanwhile are reported as disconnected.
+    Snapshot,
+}
+
+/// The result of a broadcast or a batched send.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BroadcastReport {
+    /// Count of mailboxes the message is sent to, or count of messages sent
+    /// by `send_batch`.
+    pub sent: usize,
+    /// Addresses the message can't be delivered to, with the reasons. For
+    /// `send_batch`, there is an entry for every undelivered message.
+    pub failed: Vec<(u64, DeadLetterReason)>,
+}
+
+impl BroadcastReport {
+    /// Addresses whose mailboxes are full.
+    pub fn full(&self) -> Vec<u64> {
+        self.addrs_of(DeadLetterReason::Full)
+    }
+
+    /// Addresses whose mailboxes are closed.
+    pub fn disconnected(&self) -> Vec<u64> {
+        self.addrs_of(DeadLetterReason::Disconnected)
+    }
+
+    fn addrs_of(&self, reason: DeadLetterReason) -> Vec<u64> {
+        self.failed.iter().filter(|(_, r)| *r == reason).map(|(addr, _)| *addr).collect()
+    }
+}
+
+const CACHE_SHARDS: usize = 16;
+const CACHE_CAPACITY: usize = 1024;
+
+/// A cache of mailboxes that is split into shards by address.
+///
+/// Every shard is protected by its own lock, so that a router can be shared
+/// by multiple threads without much contention on cache hits. Mailboxes are
+/// shared with the threads that send to them, so that the state of a loosely
+/// bounded sender is kept across sends.
+struct MailboxCache<N: Fsm> {
+    shards: Vec<Mutex<LruCache<u64, Arc<BasicMailbox<N>>>>>,
+}
+
+impl<N: Fsm> MailboxCache<N> {
+    fn new() -> MailboxCache<N> {
+        let shards = (0..CACHE_SHARDS)
+            .map(|_| Mutex::new(LruCache::with_capacity_and_sample(CACHE_CAPACITY / CACHE_SHARDS, 7)))
+            .collect();
+        MailboxCache { shards }
+    }
+
+    #[inline]
+    fn shard(&self, addr: u64) -> MutexGuard<'_, LruCache<u64, Arc<BasicMailbox<N>>>> {
+        self.shards[addr as usize % CACHE_SHARDS].lock().unwrap()
+    }
+
+    /// Resize the shard so that the whole cache can hold `cnt` mailboxes.
+    #[inline]
+    fn maybe_resize(shard: &mut LruCache<u64, Arc<BasicMailbox<N>>>, cnt: usize) {
+        let cnt = cnt.div_ceil(CACHE_SHARDS);
+        if cnt > shard.capacity() || cnt < shard.capacity() / 2 {
+            shard.resize(cnt);
+        }
+    }
+
+    fn remove(&self, addr: u64) {
+        self.shard(addr).remove(&addr);
+    }
+
+    fn clear(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().clear();
+        }
+    }
 }
 
 enum CheckDoResut<T> {
@@ -41,12 +354,19 @@ enum CheckDoResut<T> {
 /// missing fsm for specified address. Normal fsm and control fsm can have
 /// different scheduler, but this is not required.
 ///
+/// Missing fsms can also be created on demand by a `FsmFactory`, see
+/// `set_fsm_factory`.
+///
+/// A router is `Send + Sync` as long as its schedulers are, so it can be put
+/// in an `Arc` and shared by threads. A cloned router has its own cache,
+/// which avoids contention at the cost of warming up the cache again.
+///
 pub struct Router<N: Fsm, C: Fsm, Ns, Cs> {
-    normals: Arc<Mutex<NormalMailMap<N>>>,
-    caches: Cell<LruCache<u64, BasicMailbox<N>>>,
+    normals: Arc<NormalMailMap<N>>,
+    caches: MailboxCache<N>,
     pub(super) control_box: BasicMailbox<C>,
     pub(crate) normal_scheduler: Ns,
-    control_scheduler: Cs,
+    pub(crate) control_scheduler: Cs,
 
     // Count of Mailboxes that is not destroyed.
     // Added when a Mailbox created, and subtracted it when a Mailbox destroyed.
@@ -54,6 +374,11 @@ pub struct Router<N: Fsm, C: Fsm, Ns, Cs> {
 
     // Indicates the router is shutdown down or not.
     shutdown: Arc<AtomicBool>,
+
+    // Shared by all clones, so that it can be configured after the router is cloned.
+    dead_letter: Arc<Mutex<Option<DeadLetterSink<N::Message>>>>,
+
+    timer: Arc<Timer<N::Message>>,
 }
 
 impl<N, C, Ns, Cs> Router<N, C, Ns, Cs>
@@ -68,18 +393,18 @@ impl<N, C, Ns, Cs> Router<N, C, Ns, Cs>
         normal_scheduler: Ns,
         control_scheduler: Cs,
         state_cnt: Arc<AtomicUsize>,
+        cfg: &Config,
     ) -> Router<N, C, Ns, Cs> {
         Router {
-            normals: Arc::new(Mutex::new(NormalMailMap {
-                map: HashMap::default(),
-                alive_cnt: Arc::default(),
-            })),
-            caches: Cell::new(LruCache::with_capacity_and_sample(1024, 7)),
+            normals: Arc::new(NormalMailMap::new(cfg.router_shards)),
+            caches: MailboxCache::new(),
             control_box,
             normal_scheduler,
             control_scheduler,
             state_cnt,
             shutdown: Arc::new(AtomicBool::new(false)),
+            dead_letter: Arc::default(),
+            timer: Arc::new(Timer::new(cfg.timer_tick, cfg.timer_wheel_size)),
         }
     }
 
@@ -87,48 +412,93 @@ impl<N, C, Ns, Cs> Router<N, C, Ns, Cs>
         self.shutdown.load(Ordering::SeqCst)
     }
 
+    /// Set the sink that receives messages which can't be delivered by
+    /// `force_send`, `send_or_dead_letter` and broadcasts.
+    pub fn set_dead_letter_sink<F>(&self,sink:F)
+        where F:Fn(u64,N::Message,DeadLetterReason)+Send+Sync+'static{
+        *self.dead_letter.lock().unwrap()=Some(Arc::new(sink));
+    }
+
+    pub fn clear_dead_letter_sink(&self){
+        self.dead_letter.lock().unwrap().take();
+    }
+
+    /// Set the factory that creates fsms for addresses that have no registered
+    /// mailboxes. Once set, sending to such an address creates and registers
+    /// the fsm before the message is delivered.
+    ///
+    /// The factory is called with the address book locked, so it must not
+    /// access the router.
+    pub fn set_fsm_factory(&self,factory:impl FsmFactory<N>+'static){
+        *self.normals.factory.lock().unwrap()=Some(Box::new(factory));
+    }
+
+    pub fn clear_fsm_factory(&self){
+        self.normals.factory.lock().unwrap().take();
+    }
+
+    /// Hand the message to the dead letter sink. The message is given back if
+    /// no sink is configured.
+    fn dead_letter(&self,addr:u64,msg:N::Message,reason:DeadLetterReason) -> Option<N::Message>{
+        let sink=self.dead_letter.lock().unwrap().clone();
+        match sink {
+            Some(sink) =>{
+                sink(addr,msg,reason);
+                None
+            }
+            None=>Some(msg)
+        }
+    }
+
     #[inline]
     fn check_do<F, R>(&self, addr: u64, mut f: F) -> CheckDoResut<R>
         where F: FnMut(&BasicMailbox<N>) -> Option<R> {
 
-        let caches=unsafe{&mut *self.caches.as_ptr()};
+        // The shard is only locked to look up or update the cache, `f` may
+        // take a while, or even send through the router again. A cached mailbox
+        // that is evicted meanwhile is still valid, it's only shared.
+        let cached=self.caches.shard(addr).get(&addr).cloned();
 
         let mut connected=true;
-        if let Some(mailbox) =caches.get(&addr){
-            match f(mailbox){
+        if let Some(mailbox) =cached{
+            match f(&mailbox){
                 Some(r) => return CheckDoResut::Valid(r),
                 None=>{connected=false;}
             }
         }
 
         let (cnt,mailbox) ={
-            let mut boxes=self.normals.lock().unwrap();
-            let cnt=boxes.map.len();
-            let b=match boxes.map.get_mut(&addr) {
+            let mut boxes=self.normals.shard(addr);
+            let b=match boxes.get(&addr) {
                 Some(mailbox) =>mailbox.clone(),
                 None=>{
-                    drop(boxes);
-                    if !connected{
-                        caches.remove(&addr);
+                    let created=if self.is_shutdown(){None}else{self.normals.create(&mut boxes,addr,&self.state_cnt)};
+                    match created {
+                        Some(mailbox) =>mailbox,
+                        None=>{
+                            drop(boxes);
+                            if !connected{
+                                self.caches.remove(addr);
+                            }
+                            return CheckDoResut::NotExist;
+                        }
                     }
-                    return CheckDoResut::NotExist;
                 }
             };
-            (cnt,b)
+            (self.normals.alive_cnt.load(Ordering::Relaxed),b)
         };
-        if cnt>caches.capacity() || cnt<caches.capacity()/2{
-            caches.resize(cnt);
-        }
 
         let res=f(&mailbox);
         match res {
             Some(r) =>{
-                caches.insert(addr,mailbox);
+                let mut caches=self.caches.shard(addr);
+                MailboxCache::maybe_resize(&mut caches,cnt);
+                caches.insert(addr,Arc::new(mailbox));
                 CheckDoResut::Valid(r)
             }
             None =>{
                 if !connected{
-                    caches.remove(&addr);
+                    self.caches.remove(addr);
                 }
                 CheckDoResut::Invalid
             }
@@ -136,23 +506,42 @@ impl<N, C, Ns, Cs> Router<N, C, Ns, Cs>
 
     }
 
-    /// Register a mailbox with given address.
+    /// Register a mailbox with given address. Once `close_senders` is called,
+    /// the mailbox is closed instead of being registered.
     pub fn register(&self,addr:u64,mailbox:BasicMailbox<N>){
-        let mut normals=self.normals.lock().unwrap();
-        if let Some(mailbox) =normals.map.insert(addr,mailbox){
-            mailbox.close();
-        }
-        normals.alive_cnt.store(normals.map.len(),Ordering::Relaxed);
+        self.try_register(addr,mailbox);
     }
 
-    pub fn register_all(&self,mailboxes:Vec<(u64,BasicMailbox<N>)>){
-        let mut normals=self.normals.lock().unwrap();
-        normals.map.reserve(mailboxes.len());
-        for(addr,mailbox) in mailboxes{
-            if let Some(m) =normals.map.insert(addr,mailbox){
-                m.close();
+    /// Same as `register`, returns false if the mailbox is closed instead.
+    pub(crate) fn try_register(&self,addr:u64,mailbox:BasicMailbox<N>) -> bool{
+        let registered=mailbox.clone();
+        match self.normals.insert(addr,mailbox) {
+            Some(mailbox)=>{
+                mailbox.close();
+                !mailbox.ptr_eq(&registered)
             }
+            None=>true,
+        }
+    }
+
+    /// Register mailboxes in bulk. Returns the mailboxes that are replaced, or
+    /// rejected once `close_senders` is called, which are closed already.
+    pub fn register_all(&self,mailboxes:Vec<(u64,BasicMailbox<N>)>) -> Vec<(u64,BasicMailbox<N>)>{
+        self.register_all_impl(mailboxes,false)
+    }
+
+    /// Same as `register_all`, but the whole group becomes visible at once, a
+    /// sender never sees only part of it.
+    pub fn register_all_atomic(&self,mailboxes:Vec<(u64,BasicMailbox<N>)>) -> Vec<(u64,BasicMailbox<N>)>{
+        self.register_all_impl(mailboxes,true)
+    }
+
+    fn register_all_impl(&self,mailboxes:Vec<(u64,BasicMailbox<N>)>,atomic:bool) -> Vec<(u64,BasicMailbox<N>)>{
+        let replaced=self.normals.insert_all(mailboxes,atomic);
+        for (_,m) in &replaced{
+            m.close();
         }
+        replaced
     }
 
     /// Get the mailbox of specified address.
@@ -216,21 +605,155 @@ impl<N, C, Ns, Cs> Router<N, C, Ns, Cs>
         }
     }
 
+    /// Same as `send`, but waits for room if the mailbox blocks senders, see
+    /// `BoundPolicy::Block`. The mailbox is taken out of the router first, so
+    /// no lock of the router is held while waiting. It must not be called
+    /// from pollers or async tasks.
+    pub fn send_blocking(&self,addr:u64,msg:N::Message) -> Result<(),TrySendError<N::Message>>{
+        match self.mailbox(addr) {
+            Some(mailbox)=>mailbox.send_blocking(msg),
+            None=>Err(TrySendError::Disconnected(msg)),
+        }
+    }
+
+    /// Send messages from `msgs` to specified address in order, the fsm is
+    /// notified only once. It stops at the first message that can't be sent,
+    /// the rest are left in `msgs`. Returns the count of sent messages, and
+    /// the failed message as `try_send` does.
+    pub fn try_send_many<I>(
+        &self,
+        addr:u64,
+        msgs:&mut I
+    ) -> SendManyResult<N::Message>
+        where I:Iterator<Item=N::Message>{
+        let mut sent=0;
+        // The first message is kept if the cached mailbox turns out to be
+        // closed, so that it can be sent to the one in the address book.
+        let mut first=None;
+        let res=self.check_do(addr,|mailbox|{
+            let mut msgs=first.take().into_iter().chain(&mut *msgs);
+            let (n,res)=mailbox.try_send_many(&mut msgs,&self.normal_scheduler);
+            sent+=n;
+            match res {
+                Err(TrySendError::Disconnected(m)) if n==0=>{
+                    first=Some(m);
+                    None
+                }
+                r=>Some(r)
+            }
+        });
+
+        let res=match res {
+            CheckDoResut::Valid(r) =>Either::Left(r),
+            CheckDoResut::Invalid=>Either::Left(Err(TrySendError::Disconnected(first.unwrap()))),
+            CheckDoResut::NotExist=>match first.or_else(||msgs.next()) {
+                Some(m)=>Either::Right(m),
+                None=>Either::Left(Ok(())),
+            }
+        };
+        (sent,res)
+    }
+
+    /// Same as `try_send_many`, but a missing mailbox is reported as disconnected.
     #[inline]
-    pub fn force_send(&self,addr:u64,msg:N::Message) ->Result<(),SendError<N::Message>>{
-        match self.send(addr,msg) {
-            Ok(())=>Ok(()),
-            Err(TrySendError::Full(m))=>{
-                let caches=unsafe{&mut *self.caches.as_ptr()};
-                caches.get(&addr).unwrap().force_send(m,&self.normal_scheduler)
+    pub fn send_many<I>(&self,addr:u64,msgs:&mut I) -> (usize,Result<(),TrySendError<N::Message>>)
+        where I:Iterator<Item=N::Message>{
+        let (sent,res)=self.try_send_many(addr,msgs);
+        match res {
+            Either::Left(res) =>(sent,res),
+            Either::Right(m) =>(sent,Err(TrySendError::Disconnected(m)))
+        }
+    }
+
+    /// Send messages to their addresses. Messages are grouped by address and
+    /// every group is sent by `send_many`, so every fsm is notified once.
+    /// Messages to the same address are delivered in order, once one of them
+    /// can't be delivered, the rest of the group are given up too. Undelivered
+    /// messages are handed to the dead letter sink and reported.
+    pub fn send_batch(&self,msgs:impl IntoIterator<Item=(u64,N::Message)>) -> BroadcastReport{
+        let mut groups:Vec<(u64,Vec<N::Message>)>=vec![];
+        let mut index=HashMap::new();
+        for (addr,msg) in msgs{
+            let i=*index.entry(addr).or_insert_with(||{
+                groups.push((addr,vec![]));
+                groups.len()-1
+            });
+            groups[i].1.push(msg);
+        }
+
+        let mut report=BroadcastReport::default();
+        for (addr,msgs) in groups{
+            let mut msgs=msgs.into_iter();
+            let (sent,res)=self.try_send_many(addr,&mut msgs);
+            report.sent+=sent;
+            let (m,reason)=match res {
+                Either::Left(Ok(()))=>continue,
+                Either::Left(Err(TrySendError::Full(m)))=>(m,DeadLetterReason::Full),
+                Either::Left(Err(TrySendError::Disconnected(m)))=>(m,DeadLetterReason::Disconnected),
+                Either::Right(m)=>(m,DeadLetterReason::NotExist),
+            };
+            for m in Some(m).into_iter().chain(msgs){
+                report.failed.push((addr,reason));
+                self.dead_letter(addr,m,reason);
             }
-            Err(TrySendError::Disconnected(m))=>{
-                if self.is_shutdown(){Ok(())}
-                else{Err(SendError(m))}
+        }
+        report
+    }
+
+    /// Same as `send`, but the message is handed to the dead letter sink if it
+    /// can't be delivered. The error is only returned if no sink is configured.
+    #[inline]
+    pub fn send_or_dead_letter(&self,addr:u64,msg:N::Message) -> Result<(),TrySendError<N::Message>>{
+        let (m,reason)=match self.try_send(addr,msg) {
+            Either::Left(Ok(()))=>return Ok(()),
+            Either::Left(Err(TrySendError::Full(m)))=>(m,DeadLetterReason::Full),
+            Either::Left(Err(TrySendError::Disconnected(m)))=>(m,DeadLetterReason::Disconnected),
+            Either::Right(m)=>(m,DeadLetterReason::NotExist),
+        };
+        match self.dead_letter(addr,m,reason) {
+            None=>Ok(()),
+            Some(m) if reason==DeadLetterReason::Full=>Err(TrySendError::Full(m)),
+            Some(m)=>Err(TrySendError::Disconnected(m)),
+        }
+    }
+
+    /// Send the message regardless of the capacity limit. If the mailbox doesn't
+    /// exist or is closed, the message is handed to the dead letter sink, or
+    /// dropped silently if the router is shutdown.
+    #[inline]
+    pub fn force_send(&self,addr:u64,msg:N::Message) ->Result<(),SendError<N::Message>>{
+        let (m,reason)=match self.try_send(addr,msg) {
+            Either::Left(Ok(()))=>return Ok(()),
+            Either::Left(Err(TrySendError::Full(m)))=>{
+                let mut msg=Some(m);
+                let res=self.check_do(addr,|mailbox|{
+                    Some(mailbox.force_send(msg.take().unwrap(),&self.normal_scheduler))
+                });
+                match res {
+                    CheckDoResut::Valid(Ok(()))=>return Ok(()),
+                    CheckDoResut::Valid(Err(SendError(m)))=>(m,DeadLetterReason::Disconnected),
+                    // The mailbox is closed after the first try.
+                    _=>(msg.unwrap(),DeadLetterReason::NotExist),
+                }
             }
+            Either::Left(Err(TrySendError::Disconnected(m)))=>(m,DeadLetterReason::Disconnected),
+            Either::Right(m)=>(m,DeadLetterReason::NotExist),
+        };
+        match self.dead_letter(addr,m,reason) {
+            None=>Ok(()),
+            Some(_) if self.is_shutdown()=>Ok(()),
+            Some(m)=>Err(SendError(m)),
         }
     }
 
+    /// Send a message to specified address, waiting for capacity if the mailbox
+    /// is full. The future fails if the mailbox doesn't exist, or it's closed
+    /// or the router is shutdown before the message is sent.
+    pub fn send_async(&self,addr:u64,msg:N::Message) -> SendFuture<N,Ns>{
+        let mailbox=if self.is_shutdown(){None}else{self.mailbox(addr)};
+        SendFuture::new(mailbox,msg)
+    }
+
     #[inline]
     pub fn send_control(&self,msg:C::Message)->Result<(),TrySendError<C::Message>>{
         match self.control_box.try_send(msg,&self.control_scheduler) {
@@ -242,37 +765,262 @@ impl<N, C, Ns, Cs> Router<N, C, Ns, Cs>
         }
     }
 
+    /// Send a message built with a reply channel to specified address, and
+    /// return the handle to wait for the response.
+    pub fn ask<R,F>(&self,addr:u64,build_msg:F) -> Result<AskHandle<R>,TrySendError<N::Message>>
+        where F:FnOnce(Reply<R>)->N::Message{
+        let (reply,handle)=ask::reply_channel();
+        self.send(addr,build_msg(reply))?;
+        Ok(handle)
+    }
+
+    /// Same as `ask`, but the message is sent to the control fsm.
+    pub fn ask_control<R,F>(&self,build_msg:F) -> Result<AskHandle<R>,TrySendError<C::Message>>
+        where F:FnOnce(Reply<R>)->C::Message{
+        let (reply,handle)=ask::reply_channel();
+        self.send_control(build_msg(reply))?;
+        Ok(handle)
+    }
+
     pub fn broadcast_normal(&self,mut msg_gen:impl FnMut()->N::Message){
-        let mailboxes=self.normals.lock().unwrap();
-        for mailbox in mailboxes.map.values(){
-            let _ = mailbox.force_send(msg_gen(),&self.normal_scheduler);
+        let mut undelivered=vec![];
+        self.normals.for_each(|addr,mailbox|{
+            if let Err(SendError(m))=mailbox.force_send(msg_gen(),&self.normal_scheduler){
+                undelivered.push((addr,m));
+            }
+        });
+        // The sink may send messages through the router, so it's called
+        // after locks are released.
+        for (addr,m) in undelivered{
+            self.dead_letter(addr,m,DeadLetterReason::Disconnected);
+        }
+    }
+
+    /// Send messages to mailboxes whose addresses match `pred`. Unlike
+    /// `broadcast_normal`, capacity limits are respected. Messages that can't
+    /// be delivered are handed to the dead letter sink and reported.
+    pub fn broadcast_filtered(
+        &self,
+        mode:BroadcastMode,
+        mut pred:impl FnMut(u64)->bool,
+        mut msg_gen:impl FnMut()->N::Message
+    ) -> BroadcastReport{
+        let mut report=BroadcastReport::default();
+        let mut undelivered=vec![];
+        let mut send=|addr:u64,mailbox:&BasicMailbox<N>|{
+            match mailbox.try_send(msg_gen(),&self.normal_scheduler){
+                Ok(())=>report.sent+=1,
+                Err(TrySendError::Full(m))=>undelivered.push((addr,m,DeadLetterReason::Full)),
+                Err(TrySendError::Disconnected(m))=>undelivered.push((addr,m,DeadLetterReason::Disconnected)),
+            }
+        };
+        match mode {
+            BroadcastMode::Locked=>self.normals.for_each(|addr,mailbox|{
+                if pred(addr){
+                    send(addr,mailbox);
+                }
+            }),
+            BroadcastMode::Snapshot=>{
+                for (addr,mailbox) in self.normals.snapshot(pred){
+                    send(addr,&mailbox);
+                }
+            }
+        }
+        for (addr,m,reason) in undelivered{
+            report.failed.push((addr,reason));
+            self.dead_letter(addr,m,reason);
+        }
+        report
+    }
+
+    /// Send messages to the given addresses. Capacity limits are respected,
+    /// messages that can't be delivered are handed to the dead letter sink
+    /// and reported.
+    pub fn broadcast_to(&self,addrs:&[u64],mut msg_gen:impl FnMut()->N::Message) -> BroadcastReport{
+        let mut report=BroadcastReport::default();
+        for addr in addrs{
+            let (m,reason)=match self.try_send(*addr,msg_gen()) {
+                Either::Left(Ok(()))=>{
+                    report.sent+=1;
+                    continue;
+                }
+                Either::Left(Err(TrySendError::Full(m)))=>(m,DeadLetterReason::Full),
+                Either::Left(Err(TrySendError::Disconnected(m)))=>(m,DeadLetterReason::Disconnected),
+                Either::Right(m)=>(m,DeadLetterReason::NotExist),
+            };
+            report.failed.push((*addr,reason));
+            self.dead_letter(*addr,m,reason);
         }
+        report
+    }
+
+    /// Send the message to specified address after `delay`, see
+    /// `Config::timer_tick` for the precision. The message is sent by
+    /// `force_send` on the timer thread.
+    ///
+    /// Timers of an address are cancelled when it's closed, and all timers are
+    /// dropped when the router is shutdown. Nothing is scheduled afterwards.
+    pub fn schedule_after(&self,addr:u64,delay:Duration,msg:N::Message) -> TimerHandle
+        where Self:Send+'static,Ns:Send,Cs:Send,N::Message:'static{
+        self.start_timer();
+        self.timer.schedule_after(addr,delay,msg)
+    }
+
+    /// Send messages built by `msg_gen` to specified address every `interval`,
+    /// until the timer is cancelled. Ticks that are missed are skipped, see
+    /// `schedule_after` for other details.
+    pub fn schedule_every<F>(&self,addr:u64,interval:Duration,msg_gen:F) -> TimerHandle
+        where Self:Send+'static,Ns:Send,Cs:Send,N::Message:'static,F:FnMut()->N::Message+Send+'static{
+        self.start_timer();
+        self.timer.schedule_every(addr,interval,Box::new(msg_gen))
+    }
+
+    /// Count of timers that are not fired or cancelled yet. Recurring timers
+    /// are always pending.
+    pub fn pending_timers(&self) -> usize{
+        self.timer.len()
+    }
+
+    fn start_timer(&self) where Self:Send+'static,Ns:Send,Cs:Send,N::Message:'static{
+        self.timer.start(||{
+            let router=WeakRouter::new(self);
+            move |fired|{
+                // All routers are dropped, the thread is stopped along with the timer.
+                let router=match router.upgrade() {
+                    Some(router)=>router,
+                    None=>return,
+                };
+                for (addr,msg) in fired{
+                    let _=router.force_send(addr,msg);
+                }
+            }
+        });
     }
 
     pub fn broadcast_shutdown(&self){
         self.shutdown.store(true,Ordering::SeqCst);
-        unsafe {&mut *self.caches.as_ptr()}.clear();
-        let mut mailboxes=self.normals.lock().unwrap();
-        for (addr,mailbox) in mailboxes.map.drain(){
-            mailbox.close();
-        }
+        self.timer.stop();
+        self.caches.clear();
+        self.normals.drain(|_,mailbox|mailbox.close());
         self.control_box.close();
         self.normal_scheduler.shutdown();
         self.control_scheduler.shutdown();
     }
 
+    /// Close the senders of all mailboxes, including the control one.
+    ///
+    /// No new message can be sent afterwards, but messages that are already
+    /// queued stay in mailboxes and will still be handled. New mailboxes are
+    /// rejected too, whether they are registered or created by the factory.
+    pub fn close_senders(&self){
+        self.normals.draining.store(true,Ordering::SeqCst);
+        self.normals.for_each(|_,mailbox|mailbox.close_sender());
+        self.control_box.close_sender();
+    }
+
+    /// Check whether all mailboxes are empty, including the control one.
+    ///
+    /// Fsms don't have to be idle, one that is kept in a batch by its poller
+    /// has nothing left to handle once its mailbox is empty.
+    pub fn is_drained(&self) -> bool{
+        if !self.control_box.is_empty(){
+            return false;
+        }
+        self.normals.shards.iter().all(|shard|{
+            shard.lock().unwrap().values().all(|mailbox| mailbox.is_empty())
+        })
+    }
+
+    /// Close the mailbox of specified address, and cancel its timers.
     pub fn close(&self,addr:u64){
-        unsafe {&mut *self.caches.as_ptr()}.remove(&addr);
-        let mut mailboxes=self.normals.lock().unwrap();
-        if let Some(mb) = mailboxes.map.remove(&addr){
+        self.timer.cancel_addr(addr);
+        self.caches.remove(addr);
+        if let Some(mb) = self.normals.remove(addr){
             mb.close();
         }
+    }
+
+    /// Close mailboxes of all given addresses. Returns the removed mailboxes.
+    pub fn close_all(&self,addrs:&[u64]) -> Vec<(u64,BasicMailbox<N>)>{
+        self.close_all_impl(addrs,false)
+    }
 
-        mailboxes.alive_cnt.store(mailboxes.map.len(),Ordering::Relaxed);
+    /// Same as `close_all`, but the whole group becomes invisible at once, a
+    /// sender never sees only part of it.
+    pub fn close_all_atomic(&self,addrs:&[u64]) -> Vec<(u64,BasicMailbox<N>)>{
+        self.close_all_impl(addrs,true)
+    }
+
+    fn close_all_impl(&self,addrs:&[u64],atomic:bool) -> Vec<(u64,BasicMailbox<N>)>{
+        for addr in addrs{
+            self.timer.cancel_addr(*addr);
+            self.caches.remove(*addr);
+        }
+        let removed=self.normals.remove_all(addrs,atomic);
+        for (_,m) in &removed{
+            m.close();
+        }
+        removed
+    }
+
+    /// Close the mailbox registered at `addr` if it's the given one, and cancel
+    /// its timers. Returns false if another mailbox or none is registered there.
+    pub(crate) fn close_mailbox(&self,addr:u64,mailbox:&BasicMailbox<N>) -> bool{
+        let removed={
+            let mut shard=self.normals.shard(addr);
+            match shard.get(&addr){
+                Some(m) if m.ptr_eq(mailbox)=>self.normals.remove_in(&mut shard,addr),
+                _=>None,
+            }
+        };
+        match removed{
+            Some(mb)=>{
+                self.timer.cancel_addr(addr);
+                self.caches.remove(addr);
+                mb.close();
+                true
+            }
+            None=>false,
+        }
     }
 
     pub fn clear_cache(&self) {
-        unsafe {&mut *self.caches.as_ptr()}.clear();
+        self.caches.clear();
+    }
+
+    /// Get the queue wait histogram of the mailbox of specified address.
+    pub fn latency_of(&self,addr:u64) -> Option<Histogram>{
+        self.normals.shard(addr).get(&addr).and_then(|mailbox|mailbox.latency())
+    }
+
+    /// Get queue wait histograms of all mailboxes that track latency.
+    pub fn latencies(&self) -> Vec<(u64,Histogram)>{
+        let mut latencies=vec![];
+        self.normals.for_each(|addr,mailbox|{
+            if let Some(h)=mailbox.latency(){
+                latencies.push((addr,h));
+            }
+        });
+        latencies
+    }
+
+    /// Get the queue wait histogram across all mailboxes, including the control one.
+    pub fn latency(&self) -> Histogram{
+        let mut total=self.control_box.latency().unwrap_or_default();
+        for (_,h) in self.latencies(){
+            total+=h;
+        }
+        total
+    }
+
+    /// Get addresses of mailboxes whose queue wait time at the given quantile
+    /// exceeds `threshold`, which are likely handled by slow fsms.
+    pub fn slow_mailboxes(&self,quantile:f64,threshold:Duration) -> Vec<u64>{
+        let threshold=threshold.as_micros() as u64;
+        self.latencies().into_iter()
+            .filter(|(_,h)|h.quantile(quantile)>threshold)
+            .map(|(addr,_)|addr)
+            .collect()
     }
 
     pub fn state_cnt(&self) -> &Arc<AtomicUsize>{
@@ -280,11 +1028,11 @@ impl<N, C, Ns, Cs> Router<N, C, Ns, Cs>
     }
 
     pub fn alive_cnt(&self) -> Arc<AtomicUsize>{
-        self.normals.lock().unwrap().alive_cnt.clone()
+        self.normals.alive_cnt.clone()
     }
 
     pub fn trace(&self) -> RouterTrace{
-        let alive=self.normals.lock().unwrap().alive_cnt.clone();
+        let alive=self.normals.alive_cnt.clone();
         let total=self.state_cnt.load(Ordering::Relaxed);
         let alive=alive.load(Ordering::Relaxed);
         // 1 represents the control fsm.
@@ -309,16 +1057,60 @@ impl<N, C, Ns, Cs> Router<N, C, Ns, Cs>
 
 }
 
+/// What the timer thread keeps of a router. The timer owns the thread, so
+/// it's only kept weakly, and a router is made for every delivery.
+struct WeakRouter<N:Fsm,C:Fsm,Ns,Cs>{
+    normals:Arc<NormalMailMap<N>>,
+    control_box:BasicMailbox<C>,
+    normal_scheduler:Ns,
+    control_scheduler:Cs,
+    state_cnt:Arc<AtomicUsize>,
+    shutdown:Arc<AtomicBool>,
+    dead_letter:Arc<Mutex<Option<DeadLetterSink<N::Message>>>>,
+    timer:Weak<Timer<N::Message>>,
+}
+
+impl<N:Fsm,C:Fsm,Ns:Clone,Cs:Clone> WeakRouter<N,C,Ns,Cs>{
+    fn new(router:&Router<N,C,Ns,Cs>) -> Self{
+        WeakRouter{
+            normals:router.normals.clone(),
+            control_box:router.control_box.clone(),
+            normal_scheduler:router.normal_scheduler.clone(),
+            control_scheduler:router.control_scheduler.clone(),
+            state_cnt:router.state_cnt.clone(),
+            shutdown:router.shutdown.clone(),
+            dead_letter:router.dead_letter.clone(),
+            timer:Arc::downgrade(&router.timer)
+        }
+    }
+
+    fn upgrade(&self) -> Option<Router<N,C,Ns,Cs>>{
+        Some(Router{
+            normals:self.normals.clone(),
+            caches:MailboxCache::new(),
+            control_box:self.control_box.clone(),
+            normal_scheduler:self.normal_scheduler.clone(),
+            control_scheduler:self.control_scheduler.clone(),
+            shutdown:self.shutdown.clone(),
+            state_cnt:self.state_cnt.clone(),
+            dead_letter:self.dead_letter.clone(),
+            timer:self.timer.upgrade()?
+        })
+    }
+}
+
 impl<N:Fsm,C:Fsm,Ns:Clone,Cs:Clone> Clone for Router<N,C,Ns,Cs>{
     fn clone(&self) -> Self {
         Router{
             normals:self.normals.clone(),
-            caches:Cell::new(LruCache::with_capacity_and_sample(1024,7)),
+            caches:MailboxCache::new(),
             control_box:self.control_box.clone(),
             normal_scheduler:self.normal_scheduler.clone(),
             control_scheduler:self.control_scheduler.clone(),
             shutdown:self.shutdown.clone(),
-            state_cnt:self.state_cnt.clone()
+            state_cnt:self.state_cnt.clone(),
+            dead_letter:self.dead_letter.clone(),
+            timer:self.timer.clone()
         }
     }
 }
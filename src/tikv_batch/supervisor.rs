@@ -0,0 +1,421 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::tikv_batch::fsm::{Fsm, FsmScheduler};
+use crate::tikv_batch::mailbox::BasicMailbox;
+use crate::tikv_batch::router::{FsmFactory, Router};
+
+//region FsmFailure
+/// A normal FSM that panicked while it was handled by a poller.
+///
+/// The mailbox of the FSM is closed and removed from the router before it's
+/// reported, messages that were still queued are dropped with it. The FSM is
+/// dropped along with the failure, after its `on_drop` hook is called.
+pub struct FsmFailure<N: Fsm> {
+    /// Address of the FSM, None if its mailbox was not registered in the router.
+    pub addr: Option<u64>,
+    /// The FSM in whatever state the panic left it.
+    pub fsm: Box<N>,
+    /// The payload of the panic.
+    pub panic: Box<dyn Any + Send>,
+    /// Whether a new FSM is registered at the address, by its supervisor or
+    /// the restart factory.
+    pub restarted: bool,
+}
+
+impl<N: Fsm> FsmFailure<N> {
+    /// The message of the panic, if it's a string.
+    pub fn panic_message(&self) -> Option<&str> {
+        if let Some(s) = self.panic.downcast_ref::<&'static str>() {
+            Some(s)
+        } else {
+            self.panic.downcast_ref::<String>().map(|s| s.as_str())
+        }
+    }
+}
+
+impl<N: Fsm> fmt::Debug for FsmFailure<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FsmFailure")
+            .field("addr", &self.addr)
+            .field("panic", &self.panic_message())
+            .field("restarted", &self.restarted)
+            .finish()
+    }
+}
+
+impl<N: Fsm> Drop for FsmFailure<N> {
+    fn drop(&mut self) {
+        self.fsm.on_drop();
+    }
+}
+
+/// A handler that receives failed FSMs, it's called on the poller thread.
+pub type FailureHandler<N> = Arc<dyn Fn(FsmFailure<N>) + Send + Sync>;
+
+//endregion
+
+//region FailurePolicy
+/// How failed FSMs are dealt with. It's shared by all pollers of a batch
+/// system, so it can be changed while the system is running.
+pub(crate) struct FailurePolicy<N: Fsm> {
+    handler: Mutex<Option<FailureHandler<N>>>,
+    restart: Mutex<Option<SharedFactory<N>>>,
+    supervisors: Mutex<Vec<Supervisor<N>>>,
+}
+
+impl<N: Fsm> FailurePolicy<N> {
+    pub(crate) fn new() -> FailurePolicy<N> {
+        FailurePolicy {
+            handler: Mutex::new(None),
+            restart: Mutex::new(None),
+            supervisors: Mutex::new(vec![]),
+        }
+    }
+
+    pub(crate) fn set_handler(&self, handler: Option<FailureHandler<N>>) {
+        *self.handler.lock().unwrap() = handler;
+    }
+
+    pub(crate) fn set_restart(&self, factory: Option<SharedFactory<N>>) {
+        *self.restart.lock().unwrap() = factory;
+    }
+
+    pub(crate) fn add_supervisor(&self, supervisor: Supervisor<N>) {
+        self.supervisors.lock().unwrap().push(supervisor);
+    }
+
+    /// Remove the failed FSM from the router, let its supervisor deal with
+    /// it, or restart it if a factory is set, then report it. Without a
+    /// handler, the failure is dropped.
+    pub(crate) fn fail<C, Ns, Cs>(
+        &self,
+        router: &Router<N, C, Ns, Cs>,
+        mut fsm: Box<N>,
+        panic: Box<dyn Any + Send>,
+    ) where C: Fsm, Ns: FsmScheduler<Fsm=N> + Clone, Cs: FsmScheduler<Fsm=C> + Clone {
+        // Without its mailbox, the fsm can't be removed from the router, and
+        // messages sent to it would pile up forever.
+        let mailbox = fsm.take_mailbox()
+            .expect("a failed fsm must still own its mailbox, see `Fsm::take_mailbox`");
+        // The fsm may have been replaced or removed since it was registered,
+        // the address is only its own if the mailbox is still there.
+        let addr = mailbox.addr().filter(|addr| router.close_mailbox(*addr, &mailbox));
+        if addr.is_none() {
+            mailbox.close();
+        }
+
+        let mut failure = FsmFailure { addr, fsm, panic, restarted: false };
+        if let Some(addr) = addr {
+            // Factories and callbacks run without any lock held, they may use
+            // the batch system freely, or even fail again.
+            let plan = self.supervisors.lock().unwrap()
+                .iter_mut()
+                .find_map(|s| s.on_failure(addr));
+            failure.restarted = match plan {
+                Some(plan) => plan.run(router),
+                None => {
+                    let factory = self.restart.lock().unwrap().clone();
+                    match factory {
+                        Some(factory) => start_fsm(router, addr, &*factory),
+                        None => false,
+                    }
+                }
+            };
+        }
+
+        self.report(failure);
+    }
+
+    fn report(&self, failure: FsmFailure<N>) {
+        let handler = self.handler.lock().unwrap().clone();
+        if let Some(handler) = handler {
+            handler(failure);
+        }
+    }
+}
+
+//endregion
+
+//region Supervisor
+/// Which children a supervisor restarts when one of them fails.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RestartStrategy {
+    /// Only the failed child is restarted.
+    OneForOne,
+    /// All children are stopped and started again.
+    OneForAll,
+    /// The failed child and the children added after it are stopped and
+    /// started again.
+    RestForOne,
+}
+
+/// A supervisor gives up once it's asked to restart children more than
+/// `max_restarts` times within `period`. It stops all its children, and it's
+/// restarted as a failed child by its parent supervisor.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RestartIntensity {
+    pub max_restarts: usize,
+    pub period: Duration,
+}
+
+impl Default for RestartIntensity {
+    fn default() -> RestartIntensity {
+        RestartIntensity {
+            max_restarts: 3,
+            period: Duration::from_secs(5),
+        }
+    }
+}
+
+type EscalateHandler = Arc<dyn Fn(&str) + Send + Sync>;
+
+// Factories are shared, so that they can be called after the tree is unlocked.
+pub(crate) type SharedFactory<N> = Arc<dyn FsmFactory<N> + Sync>;
+
+enum Child<N: Fsm> {
+    Fsm {
+        addr: u64,
+        factory: SharedFactory<N>,
+    },
+    Supervisor(Supervisor<N>),
+}
+
+/// A node of a supervision tree, whose children are normal FSMs and other
+/// supervisors. Children are started in the order they are added, and
+/// stopped in the reverse order.
+///
+/// A tree is started by `BatchSystem::supervise`. Once an FSM in the tree
+/// panics, its supervisor restarts it, and maybe its siblings, according
+/// to the restart strategy. Stopping an FSM closes its mailbox, starting it
+/// registers a new one created by its factory at the same address.
+pub struct Supervisor<N: Fsm> {
+    name: String,
+    strategy: RestartStrategy,
+    intensity: RestartIntensity,
+    // When children are restarted, within the period of `intensity`.
+    restarts: VecDeque<Instant>,
+    children: Vec<Child<N>>,
+    on_escalate: Option<EscalateHandler>,
+}
+
+impl<N: Fsm> Supervisor<N> {
+    pub fn new(name: impl Into<String>, strategy: RestartStrategy) -> Supervisor<N> {
+        Supervisor {
+            name: name.into(),
+            strategy,
+            intensity: RestartIntensity::default(),
+            restarts: VecDeque::new(),
+            children: vec![],
+            on_escalate: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn intensity(mut self, max_restarts: usize, period: Duration) -> Supervisor<N> {
+        self.intensity = RestartIntensity { max_restarts, period };
+        self
+    }
+
+    /// Add an FSM at `addr`, which is created by `factory` whenever it's started.
+    pub fn child(mut self, addr: u64, factory: impl FsmFactory<N> + Sync + 'static) -> Supervisor<N> {
+        self.children.push(Child::Fsm { addr, factory: Arc::new(factory) });
+        self
+    }
+
+    pub fn supervisor(mut self, supervisor: Supervisor<N>) -> Supervisor<N> {
+        self.children.push(Child::Supervisor(supervisor));
+        self
+    }
+
+    /// Set the function that's called with the name of the root supervisor
+    /// when it gives up, all FSMs of the tree are stopped by then. It's only
+    /// used by the root, other supervisors escalate to their parents.
+    pub fn on_escalate(mut self, f: impl Fn(&str) + Send + Sync + 'static) -> Supervisor<N> {
+        self.on_escalate = Some(Arc::new(f));
+        self
+    }
+
+    pub(crate) fn start<C, Ns, Cs>(&mut self, router: &Router<N, C, Ns, Cs>)
+        where C: Fsm, Ns: FsmScheduler<Fsm=N> + Clone, Cs: FsmScheduler<Fsm=C> + Clone {
+        let mut plan = RestartPlan::new(None);
+        self.start_all(&mut plan);
+        plan.run(router);
+    }
+
+    fn start_all(&mut self, plan: &mut RestartPlan<N>) {
+        for i in 0..self.children.len() {
+            self.start_child(i, plan);
+        }
+    }
+
+    fn stop(&self, plan: &mut RestartPlan<N>) {
+        for i in (0..self.children.len()).rev() {
+            self.stop_child(i, plan);
+        }
+    }
+
+    fn start_child(&mut self, i: usize, plan: &mut RestartPlan<N>) {
+        match self.children[i] {
+            Child::Fsm { addr, ref factory } => plan.steps.push(Step::Start(addr, factory.clone())),
+            Child::Supervisor(ref mut s) => {
+                s.restarts.clear();
+                s.start_all(plan);
+            }
+        }
+    }
+
+    fn stop_child(&self, i: usize, plan: &mut RestartPlan<N>) {
+        match self.children[i] {
+            Child::Fsm { addr, .. } => plan.steps.push(Step::Stop(addr)),
+            Child::Supervisor(ref s) => s.stop(plan),
+        }
+    }
+
+    /// Record a restart, returns false if the intensity is exceeded.
+    fn allow_restart(&mut self) -> bool {
+        let now = Instant::now();
+        while let Some(t) = self.restarts.front() {
+            if now.duration_since(*t) <= self.intensity.period {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.intensity.max_restarts {
+            return false;
+        }
+        self.restarts.push_back(now);
+        true
+    }
+
+    fn restart(&mut self, failed: usize, plan: &mut RestartPlan<N>) {
+        let range = match self.strategy {
+            RestartStrategy::OneForOne => failed..failed + 1,
+            RestartStrategy::OneForAll => 0..self.children.len(),
+            RestartStrategy::RestForOne => failed..self.children.len(),
+        };
+        for i in range.clone().rev() {
+            self.stop_child(i, plan);
+        }
+        for i in range {
+            self.start_child(i, plan);
+        }
+    }
+
+    // Indexes of children from the root down to the FSM at `addr`.
+    fn path_of(&self, addr: u64) -> Option<Vec<usize>> {
+        for (i, child) in self.children.iter().enumerate() {
+            match child {
+                Child::Fsm { addr: a, .. } if *a == addr => return Some(vec![i]),
+                Child::Fsm { .. } => {}
+                Child::Supervisor(s) => {
+                    if let Some(mut path) = s.path_of(addr) {
+                        path.insert(0, i);
+                        return Some(path);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn node_mut(&mut self, path: &[usize]) -> &mut Supervisor<N> {
+        let mut node = self;
+        for i in path {
+            node = match node.children[*i] {
+                Child::Supervisor(ref mut s) => s,
+                Child::Fsm { .. } => unreachable!(),
+            };
+        }
+        node
+    }
+
+    /// Decide how to deal with the failed FSM at `addr`. Returns None if it's
+    /// not in the tree, otherwise the plan to carry out, which is done after
+    /// the tree is unlocked.
+    pub(crate) fn on_failure(&mut self, addr: u64) -> Option<RestartPlan<N>> {
+        let path = self.path_of(addr)?;
+        let mut plan = RestartPlan::new(Some(addr));
+        // The supervisor at `path[..depth]` deals with its failed child at
+        // `path[depth]`, or gives up and fails itself.
+        let mut depth = path.len() - 1;
+        loop {
+            let node = self.node_mut(&path[..depth]);
+            if node.allow_restart() {
+                node.restart(path[depth], &mut plan);
+                return Some(plan);
+            }
+            node.stop(&mut plan);
+            if depth == 0 {
+                break;
+            }
+            depth -= 1;
+        }
+        plan.escalate = self.on_escalate.clone().map(|f| (self.name.clone(), f));
+        Some(plan)
+    }
+}
+
+enum Step<N: Fsm> {
+    Stop(u64),
+    Start(u64, SharedFactory<N>),
+}
+
+/// What a supervision tree decided to do about a failure.
+pub(crate) struct RestartPlan<N: Fsm> {
+    // Mailboxes to close and FSMs to start, in order.
+    steps: Vec<Step<N>>,
+    // The name of the root and its handler, if the root gives up.
+    escalate: Option<(String, EscalateHandler)>,
+    // The address of the failed FSM, if the plan deals with a failure.
+    failed: Option<u64>,
+}
+
+impl<N: Fsm> RestartPlan<N> {
+    fn new(failed: Option<u64>) -> RestartPlan<N> {
+        RestartPlan {
+            steps: vec![],
+            escalate: None,
+            failed,
+        }
+    }
+
+    /// Carry out the plan, returns whether a new FSM is registered at the
+    /// address of the failed one.
+    pub(crate) fn run<C, Ns, Cs>(self, router: &Router<N, C, Ns, Cs>) -> bool
+        where C: Fsm, Ns: FsmScheduler<Fsm=N> + Clone, Cs: FsmScheduler<Fsm=C> + Clone {
+        let mut restarted = false;
+        for step in self.steps {
+            match step {
+                Step::Stop(addr) => router.close(addr),
+                Step::Start(addr, factory) => {
+                    let started = start_fsm(router, addr, &*factory);
+                    if Some(addr) == self.failed {
+                        restarted = started;
+                    }
+                }
+            }
+        }
+        if let Some((name, f)) = self.escalate {
+            f(&name);
+        }
+        restarted
+    }
+}
+
+/// Register the FSM that `factory` creates at `addr`. Returns false if it
+/// creates none, or the router rejects it because it's shutting down.
+fn start_fsm<N, C, Ns, Cs>(router: &Router<N, C, Ns, Cs>, addr: u64, factory: &dyn FsmFactory<N>) -> bool
+    where N: Fsm, C: Fsm, Ns: FsmScheduler<Fsm=N> + Clone, Cs: FsmScheduler<Fsm=C> + Clone {
+    match factory.create(addr) {
+        Some((sender, fsm)) => router.try_register(addr, BasicMailbox::new(sender, fsm, router.state_cnt().clone())),
+        None => false,
+    }
+}
+
+//endregion
@@ -1,13 +1,14 @@
 use crate::tikv_batch::test_runner::{Runner, Builder, HandleMetrics, Message, Handler};
-use crate::tikv_batch::batch::{create_system, PollHandler};
+use crate::tikv_batch::batch::{create_system, BatchSystem, PollHandler, HandlerBuilder};
 use crate::tikv_batch::config::Config;
 use crate::tikv_batch::mpsc::unbounded;
 use crate::tikv_batch::mailbox::BasicMailbox;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::thread::sleep;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use crate::tikv_batch::fsm::{Priority, Fsm};
+use crate::tikv_batch::reschedule::{BatchStats, BuiltinReschedulePolicy, ReschedulePolicy};
 
 #[test]
 fn test_batch() {
@@ -102,4 +103,798 @@ fn test_priority(){
     }))).unwrap();
     assert_eq!(rx.recv_timeout(Duration::from_secs(3)),Ok(3));
 
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_scale_pool() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let mut cfg = Config::default();
+    cfg.pool_sizes[Priority::Normal] = 1;
+    let (router, mut system) = create_system(&cfg, ctrl_tx, ctrl_fsm);
+    system.spawn("test-scale".to_owned(), Builder::new());
+
+    let (tx, rx) = unbounded();
+    let tx_ = tx.clone();
+    let r = router.clone();
+    router.send_control(Message::Callback(Box::new(
+        move |_: &Handler, _: &mut Runner| {
+            let (tx, runner) = Runner::new(10);
+            r.register(1, BasicMailbox::new(tx, runner, Arc::default()));
+            tx_.send(0).unwrap();
+        }
+    ))).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(0));
+
+    let ping = |id: usize| {
+        let tx = tx.clone();
+        Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+            tx.send(id).unwrap();
+        }))
+    };
+
+    system.scale_pool(Priority::Normal, 4);
+    assert_eq!(system.pool_size(Priority::Normal), 4);
+    router.send(1, ping(1)).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(1));
+
+    // Keep the fsm busy while pollers are retired.
+    for _ in 0..5 {
+        router.force_send(1, Message::Loop(1000)).unwrap();
+    }
+    system.scale_pool(Priority::Normal, 1);
+    assert_eq!(system.pool_size(Priority::Normal), 1);
+    router.force_send(1, ping(2)).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(2));
+
+    // Fsms scheduled while there is no poller should be handled once the
+    // pool grows again.
+    system.scale_pool(Priority::Normal, 0);
+    router.force_send(1, ping(3)).unwrap();
+    router.send_control(ping(4)).unwrap();
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    system.scale_pool(Priority::Normal, 2);
+    let mut received = vec![
+        rx.recv_timeout(Duration::from_secs(3)).unwrap(),
+        rx.recv_timeout(Duration::from_secs(3)).unwrap(),
+    ];
+    received.sort_unstable();
+    assert_eq!(received, vec![3, 4]);
+
+    // Retired pollers leave the registry, their metrics are kept by class.
+    let metrics = system.metrics();
+    let names: Vec<_> = metrics.pollers.iter()
+        .filter(|p| p.priority == Priority::Normal)
+        .map(|p| &p.name)
+        .collect();
+    assert_eq!(names.len(), 2);
+    assert_ne!(names[0], names[1]);
+    assert!(metrics.retired[Priority::Normal].rounds > 0);
+
+    system.shutdown();
+}
+
+
+#[test]
+fn test_graceful_shutdown() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-graceful".to_owned(), Builder::new());
+
+    let (tx, rx) = unbounded();
+    let r = router.clone();
+    router.send_control(Message::Callback(Box::new(
+        move |_: &Handler, _: &mut Runner| {
+            for addr in 1..=2 {
+                let (tx, runner) = Runner::new(10);
+                r.register(addr, BasicMailbox::new(tx, runner, Arc::default()));
+            }
+            tx.send(()).unwrap();
+        }
+    ))).unwrap();
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    for addr in 1..=2 {
+        router.force_send(addr, Message::Callback(Box::new(|_: &Handler, _: &mut Runner| {
+            sleep(Duration::from_millis(50));
+        }))).unwrap();
+        for _ in 0..100 {
+            let c = counter.clone();
+            router.force_send(addr, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+                c.fetch_add(1, Ordering::SeqCst);
+            }))).unwrap();
+        }
+    }
+
+    // No mailbox can be added while the system is drained.
+    router.close_senders();
+    let (tx, runner) = Runner::new(10);
+    router.register(3, BasicMailbox::new(tx, runner, Arc::default()));
+    assert!(router.mailbox(3).is_none());
+    let created = Arc::new(AtomicUsize::new(0));
+    let created_ = created.clone();
+    router.set_fsm_factory(move |_: u64| {
+        created_.fetch_add(1, Ordering::SeqCst);
+        Some(Runner::new(10))
+    });
+    assert!(router.send(4, Message::Loop(1)).is_err());
+    assert_eq!(created.load(Ordering::SeqCst), 0);
+
+    assert!(system.graceful_shutdown(Duration::from_secs(3)));
+    assert_eq!(counter.load(Ordering::SeqCst), 200);
+    assert!(router.send(1, Message::Loop(1)).is_err());
+}
+
+#[test]
+fn test_graceful_shutdown_timeout() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-graceful-timeout".to_owned(), Builder::new());
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    router.send_control(Message::Callback(Box::new(|_: &Handler, _: &mut Runner| {
+        sleep(Duration::from_millis(300));
+    }))).unwrap();
+    let control_mailbox = router.control_mailbox();
+    for _ in 0..100 {
+        let c = counter.clone();
+        control_mailbox.force_send(Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+            c.fetch_add(1, Ordering::SeqCst);
+        }))).unwrap();
+    }
+
+    // Messages still queued after the deadline are dropped.
+    assert!(!system.graceful_shutdown(Duration::from_millis(50)));
+    assert!(counter.load(Ordering::SeqCst) < 100);
+}
+
+
+#[test]
+fn test_shutdown_many_pollers() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let mut cfg = Config::default();
+    cfg.pool_sizes[Priority::Normal] = 200;
+    cfg.pool_sizes[Priority::Low] = 20;
+    let (router, mut system) = create_system(&cfg, ctrl_tx, ctrl_fsm);
+    system.spawn("test-many".to_owned(), Builder::new());
+
+    let (tx, rx) = unbounded();
+    router.send_control(Message::Callback(Box::new(
+        move |_: &Handler, _: &mut Runner| {
+            tx.send(1).unwrap();
+        }
+    ))).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(1));
+
+    let (tx, rx) = unbounded();
+    let handle = std::thread::spawn(move || {
+        system.shutdown();
+        tx.send(()).unwrap();
+    });
+    rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    handle.join().unwrap();
+}
+
+
+#[test]
+fn test_metrics() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-metrics".to_owned(), Builder::new());
+
+    let (tx, rx) = unbounded();
+    let tx_ = tx.clone();
+    let r = router.clone();
+    router.send_control(Message::Callback(Box::new(
+        move |_: &Handler, _: &mut Runner| {
+            let (tx, runner) = Runner::new(10);
+            r.register(1, BasicMailbox::new(tx, runner, Arc::default()));
+            tx_.send(0).unwrap();
+        }
+    ))).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(0));
+
+    for i in 1..=3 {
+        let tx = tx.clone();
+        router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+            tx.send(i).unwrap();
+        }))).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(i));
+    }
+
+    let handle = system.metrics_handle();
+    system.shutdown();
+    let snapshot = handle.snapshot();
+    assert_eq!(snapshot.pollers.len(), 3);
+    assert_eq!(snapshot.queue_lens.len(), Priority::COUNT);
+
+    let total = snapshot.total;
+    assert_eq!(total.control_handled, 1);
+    assert_eq!(total.normal_handled, 3);
+    assert_eq!(total.released, 3);
+    assert_eq!(total.rounds, 4);
+    assert_eq!(total.batch_size.count(), 4);
+    assert_eq!(total.batch_size.sum(), 3);
+    assert_eq!(total.batch_size.max(), 1);
+    assert_eq!(
+        snapshot.pollers.iter().map(|p| p.metrics.rounds).sum::<u64>(),
+        total.rounds
+    );
+}
+
+#[test]
+fn test_weighted_fair() {
+    use crate::tikv_batch::fsm::PerPriority;
+
+    // Only the high priority pool has pollers.
+    let cfg = Config {
+        pool_sizes: PerPriority::from_fn(|p| if p == Priority::High { 1 } else { 0 }),
+        weighted_fair: true,
+        ..Config::default()
+    };
+    let (tx, rx) = unbounded();
+    let report = |id: usize| {
+        let tx = tx.clone();
+        Message::Callback(Box::new(move |h: &Handler, r: &mut Runner| {
+            tx.send((id, h.get_priority(), r.get_priority())).unwrap();
+        }))
+    };
+
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&cfg, ctrl_tx, ctrl_fsm);
+    system.spawn("test-weighted-fair".to_owned(), Builder::new());
+    for (addr, priority) in [(1, Priority::High), (2, Priority::Background)] {
+        let (tx, mut runner) = Runner::new(10);
+        runner.set_priority(priority);
+        router.register(addr, BasicMailbox::new(tx, runner, Arc::default()));
+    }
+    // Idle high priority pollers take FSMs of lower classes.
+    router.send(2, report(2)).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok((2, Priority::High, Priority::Background)));
+    router.send(1, report(1)).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok((1, Priority::High, Priority::High)));
+    router.send_control(report(0)).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok((0, Priority::High, Priority::Normal)));
+    system.shutdown();
+
+    // Without weighted-fair mode, every class waits for its own pool.
+    let cfg = Config { weighted_fair: false, ..cfg };
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&cfg, ctrl_tx, ctrl_fsm);
+    system.spawn("test-isolated".to_owned(), Builder::new());
+    let (tx, mut runner) = Runner::new(10);
+    runner.set_priority(Priority::Background);
+    router.register(2, BasicMailbox::new(tx, runner, Arc::default()));
+    router.send(2, report(2)).unwrap();
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    system.scale_pool(Priority::Background, 1);
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok((2, Priority::Background, Priority::Background)));
+    system.shutdown();
+}
+
+/// Wait until pollers flush metrics that show `n` stolen fsms, which
+/// happens at the end of the round that handles them.
+fn wait_stolen(system: &BatchSystem<Runner, Runner>, n: u64) {
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while system.metrics().total.stolen < n && Instant::now() < deadline {
+        sleep(Duration::from_millis(10));
+    }
+    assert_eq!(system.metrics().total.stolen, n);
+}
+
+#[test]
+fn test_work_stealing() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let mut cfg = Config {
+        work_stealing: true,
+        ..Config::default()
+    };
+    cfg.pool_sizes[Priority::Normal] = 1;
+    let (router, mut system) = create_system(&cfg, ctrl_tx, ctrl_fsm);
+    system.spawn("test-stealing".to_owned(), Builder::new());
+    for addr in 1..=2 {
+        let (tx, runner) = Runner::new(100);
+        router.register(addr, BasicMailbox::new(tx, runner, Arc::default()));
+    }
+
+    // Hold the only poller, so both fsms join its batch in the same round.
+    let (gate_tx, gate_rx) = crossbeam::channel::unbounded::<()>();
+    let gate_rx_ = gate_rx.clone();
+    router.send_control(Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        let _ = gate_rx_.recv_timeout(Duration::from_secs(10));
+    }))).unwrap();
+    // More messages than a round handles, so both fsms are kept in the local
+    // queue, and fsm 1 blocks the poller in the next round.
+    let (tx, rx) = unbounded();
+    for addr in 1..=2 {
+        for _ in 0..20 {
+            router.send(addr, Message::Loop(0)).unwrap();
+        }
+    }
+    let tx_ = tx.clone();
+    router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        tx_.send(1).unwrap();
+        let _ = gate_rx.recv_timeout(Duration::from_secs(10));
+    }))).unwrap();
+    router.send(2, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        tx.send(2).unwrap();
+    }))).unwrap();
+    gate_tx.send(()).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(1));
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+    // A new poller steals fsm 2 from the blocked one.
+    system.scale_pool(Priority::Normal, 2);
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(2));
+    wait_stolen(&system, 1);
+
+    gate_tx.send(()).unwrap();
+    system.shutdown();
+}
+
+#[test]
+fn test_wake_idle_poller_to_steal() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let mut cfg = Config {
+        work_stealing: true,
+        ..Config::default()
+    };
+    cfg.pool_sizes[Priority::Normal] = 1;
+    let (router, mut system) = create_system(&cfg, ctrl_tx, ctrl_fsm);
+    system.spawn("test-wake-stealing".to_owned(), Builder::new());
+    for addr in 1..=2 {
+        let (tx, runner) = Runner::new(100);
+        router.register(addr, BasicMailbox::new(tx, runner, Arc::default()));
+    }
+
+    // Hold the only poller, so both fsms join its batch in the same round.
+    let (ctrl_gate_tx, ctrl_gate_rx) = crossbeam::channel::unbounded::<()>();
+    router.send_control(Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        let _ = ctrl_gate_rx.recv_timeout(Duration::from_secs(10));
+    }))).unwrap();
+    // Fsm 2 blocks the poller in the first round, with fsm 1 in the batch.
+    // Both are left with messages, and fsm 1 blocks the poller in the next
+    // round, while fsm 2 waits in the local queue.
+    let (tx, rx) = unbounded();
+    let (blocked_tx, blocked_rx) = unbounded();
+    let (gate_tx, gate_rx) = crossbeam::channel::unbounded::<()>();
+    for _ in 0..20 {
+        router.send(1, Message::Loop(0)).unwrap();
+    }
+    let (tx_, gate_rx_) = (tx.clone(), gate_rx.clone());
+    router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        tx_.send(1).unwrap();
+        let _ = gate_rx_.recv_timeout(Duration::from_secs(10));
+    }))).unwrap();
+    router.send(2, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        blocked_tx.send(()).unwrap();
+        let _ = gate_rx.recv_timeout(Duration::from_secs(10));
+    }))).unwrap();
+    for _ in 0..20 {
+        router.send(2, Message::Loop(0)).unwrap();
+    }
+    router.send(2, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        tx.send(2).unwrap();
+    }))).unwrap();
+    ctrl_gate_tx.send(()).unwrap();
+    blocked_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+
+    // The new poller finds nothing to steal and goes idle.
+    system.scale_pool(Priority::Normal, 2);
+    sleep(Duration::from_millis(100));
+    assert_eq!(system.metrics().total.stolen, 0);
+
+    // It's woken up to steal fsm 2 once the other poller is blocked by fsm 1.
+    gate_tx.send(()).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(1));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(2));
+    wait_stolen(&system, 1);
+
+    gate_tx.send(()).unwrap();
+    system.shutdown();
+}
+
+/// Compares the queue wait time of messages under a skewed load, where one
+/// fsm is much slower than others and every fsm gets more messages than a
+/// round handles, so they stay in the batches of pollers. Only messages of
+/// the fast fsms are measured. Run it by
+/// `cargo test --release bench_work_stealing -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_work_stealing() {
+    let fsms = 32;
+    let rounds = 50;
+    let burst = 20;
+    for work_stealing in [false, true] {
+        let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+        let mut cfg = Config {
+            work_stealing,
+            ..Config::default()
+        };
+        cfg.pool_sizes[Priority::Normal] = 4;
+        let (router, mut system) = create_system(&cfg, ctrl_tx, ctrl_fsm);
+        system.spawn("bench-stealing".to_owned(), Builder::new());
+        for addr in 0..fsms {
+            let (tx, runner) = Runner::new(usize::MAX);
+            router.register(addr, BasicMailbox::new(tx, runner, Arc::default()));
+        }
+
+        let (tx, rx) = unbounded();
+        for _ in 0..rounds {
+            for addr in 0..fsms {
+                // The slow fsm always has a backlog, so it never leaves the batch.
+                let burst = if addr == 0 { 2 * burst } else { burst };
+                for _ in 0..burst {
+                    let tx = tx.clone();
+                    let sent = Instant::now();
+                    router.send(addr, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+                        // Handling blocks for a while, like waiting for IO.
+                        if addr == 0 {
+                            sleep(Duration::from_micros(200));
+                        } else {
+                            tx.send(sent.elapsed()).unwrap();
+                            sleep(Duration::from_micros(20));
+                        }
+                    }))).unwrap();
+                }
+            }
+            sleep(Duration::from_millis(50));
+        }
+        let mut waits: Vec<_> = (0..(fsms - 1) * rounds * burst)
+            .map(|_| rx.recv_timeout(Duration::from_secs(30)).unwrap())
+            .collect();
+        waits.sort_unstable();
+        let percentile = |p: f64| waits[((waits.len() - 1) as f64 * p) as usize];
+        println!(
+            "work_stealing: {}, p50: {:?}, p99: {:?}, p999: {:?}, max: {:?}, stolen: {}",
+            work_stealing,
+            percentile(0.5),
+            percentile(0.99),
+            percentile(0.999),
+            waits[waits.len() - 1],
+            system.metrics().total.stolen
+        );
+        system.shutdown();
+    }
+}
+
+type Begins = Arc<Mutex<Vec<(usize, Option<Duration>)>>>;
+
+/// Records the arguments of every `begin` call.
+struct BeginRecorder {
+    inner: Handler,
+    begins: Begins,
+}
+
+impl PollHandler<Runner, Runner> for BeginRecorder {
+    fn begin(&mut self, batch_size: usize, time_budget: Option<Duration>) {
+        self.begins.lock().unwrap().push((batch_size, time_budget));
+        self.inner.begin(batch_size, time_budget)
+    }
+
+    fn handle_control(&mut self, control: &mut Runner) -> Option<usize> {
+        self.inner.handle_control(control)
+    }
+
+    fn handle_normal(&mut self, normal: &mut Runner, stats: &mut BatchStats) -> Option<usize> {
+        self.inner.handle_normal(normal, stats)
+    }
+
+    fn end(&mut self, batch: &mut [Box<Runner>]) {
+        self.inner.end(batch)
+    }
+}
+
+struct BeginRecorderBuilder {
+    inner: Builder,
+    begins: Begins,
+}
+
+impl HandlerBuilder<Runner, Runner> for BeginRecorderBuilder {
+    type Handler = BeginRecorder;
+
+    fn build(&mut self, priority: Priority) -> BeginRecorder {
+        BeginRecorder {
+            inner: self.inner.build(priority),
+            begins: self.begins.clone(),
+        }
+    }
+}
+
+#[test]
+fn test_adaptive_batch_size() {
+    let budget = Duration::from_millis(10);
+    let mut cfg = Config {
+        batch_time_budget: Some(budget),
+        ..Config::default()
+    };
+    cfg.pool_sizes[Priority::Normal] = 1;
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&cfg, ctrl_tx, ctrl_fsm);
+    let begins = Arc::new(Mutex::new(vec![]));
+    system.spawn("test-adaptive".to_owned(), BeginRecorderBuilder {
+        inner: Builder::new(),
+        begins: begins.clone(),
+    });
+
+    // Every fsm takes about 2ms, so about 5 of them fit in the budget. The
+    // first round uses the max batch size, the later ones are smaller.
+    let (tx, rx) = unbounded();
+    for addr in 1..=20 {
+        let (sender, runner) = Runner::new(10);
+        router.register(addr, BasicMailbox::new(sender, runner, Arc::default()));
+    }
+    for _ in 0..2 {
+        for addr in 1..=20 {
+            let tx = tx.clone();
+            router.send(addr, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+                sleep(Duration::from_millis(2));
+                tx.send(()).unwrap();
+            }))).unwrap();
+        }
+        for _ in 1..=20 {
+            rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        }
+    }
+    system.shutdown();
+
+    let begins = begins.lock().unwrap();
+    assert_eq!(begins[0], (cfg.max_batch_size(), Some(budget)));
+    assert!(begins.iter().all(|(_, b)| *b == Some(budget)));
+    let (min_size, _) = *begins.iter().min().unwrap();
+    assert!(min_size < 10, "{:?}", begins);
+}
+
+#[test]
+fn test_reschedule_policy() {
+    // Returns the count of rescheduled fsms after a busy fsm handles 100
+    // messages in a row.
+    let run = |policy: BuiltinReschedulePolicy, custom: Option<Arc<AtomicUsize>>| {
+        let mut cfg = Config {
+            reschedule_policy: policy,
+            ..Config::default()
+        };
+        cfg.pool_sizes[Priority::Normal] = 1;
+        let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+        let (router, mut system) = create_system(&cfg, ctrl_tx, ctrl_fsm);
+        if let Some(calls) = custom {
+            system.set_reschedule_policy(move || {
+                let calls = calls.clone();
+                Box::new(FnPolicy(move |stats: &BatchStats| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    stats.messages >= 32
+                })) as Box<dyn ReschedulePolicy>
+            });
+        }
+        system.spawn("test-reschedule".to_owned(), Builder::new());
+        let (sender, runner) = Runner::new(200);
+        router.register(1, BasicMailbox::new(sender, runner, Arc::default()));
+
+        // Fill the mailbox before the fsm is scheduled.
+        let (tx, rx) = unbounded();
+        let (gate_tx, gate_rx) = unbounded::<()>();
+        router.send_control(Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+            let _ = gate_rx.recv_timeout(Duration::from_secs(10));
+        }))).unwrap();
+        for _ in 0..99 {
+            router.send(1, Message::Loop(0)).unwrap();
+        }
+        router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+            tx.send(()).unwrap();
+        }))).unwrap();
+        gate_tx.send(()).unwrap();
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        let rescheduled = system.metrics().total.rescheduled;
+        system.shutdown();
+        rescheduled
+    };
+
+    assert_eq!(run(BuiltinReschedulePolicy::Never, None), 0);
+    // Handled in rounds of 16 messages, rescheduled at 16, 32, ..., 96.
+    assert_eq!(run(BuiltinReschedulePolicy::MessageBudget(16), None), 6);
+    // Stats are reset once the fsm leaves the batch, so it's rescheduled
+    // after every 2 rounds.
+    let calls = Arc::new(AtomicUsize::new(0));
+    assert_eq!(run(BuiltinReschedulePolicy::Never, Some(calls.clone())), 3);
+    assert_eq!(calls.load(Ordering::SeqCst), 7);
+}
+
+/// A reschedule policy defined by a closure.
+struct FnPolicy<F>(F);
+
+impl<F: FnMut(&BatchStats) -> bool + Send> ReschedulePolicy for FnPolicy<F> {
+    fn should_reschedule(&mut self, stats: &BatchStats) -> bool {
+        (self.0)(stats)
+    }
+}
+
+#[test]
+fn test_lifecycle_hooks() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-lifecycle-hooks".to_owned(), Builder::new());
+
+    let mut hooks = vec![];
+    for addr in 1..=2 {
+        let (tx, rx) = unbounded();
+        let (sender, mut runner) = Runner::new(10);
+        runner.lifecycle = Some(tx);
+        router.register(addr, BasicMailbox::new(sender, runner, Arc::default()));
+        hooks.push(rx);
+    }
+    let next_hook = |addr: usize| hooks[addr - 1].recv_timeout(Duration::from_secs(3)).unwrap();
+
+    router.send(1, Message::Loop(1)).unwrap();
+    assert_eq!(next_hook(1), "start");
+    assert_eq!(next_hook(1), "idle");
+    router.send(1, Message::Loop(1)).unwrap();
+    assert_eq!(next_hook(1), "idle");
+    // An FSM that still has messages at the end of a round stays in the
+    // batch, it only goes idle once all of them are handled.
+    let (gate_tx, gate_rx) = unbounded::<()>();
+    router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        let _ = gate_rx.recv_timeout(Duration::from_secs(3));
+    }))).unwrap();
+    for _ in 0..20 {
+        router.force_send(1, Message::Loop(1)).unwrap();
+    }
+    gate_tx.send(()).unwrap();
+    assert_eq!(next_hook(1), "idle");
+    sleep(Duration::from_millis(50));
+    assert!(hooks[0].try_recv().is_err());
+    router.send(1, Message::Callback(Box::new(|_: &Handler, r: &mut Runner| r.stop()))).unwrap();
+    assert_eq!(next_hook(1), "stop");
+    // A stopped FSM is stopped only once, and it doesn't go idle anymore.
+    router.send(1, Message::Loop(1)).unwrap();
+    sleep(Duration::from_millis(50));
+    assert!(hooks[0].try_recv().is_err());
+    router.close(1);
+    assert_eq!(next_hook(1), "drop");
+
+    // The FSM is still being handled at shutdown.
+    router.send(2, Message::Callback(Box::new(|_: &Handler, _: &mut Runner| {
+        sleep(Duration::from_millis(100));
+    }))).unwrap();
+    assert_eq!(next_hook(2), "start");
+    system.shutdown();
+    let mut last = next_hook(2);
+    while let Ok(hook) = hooks[1].try_recv() {
+        last = hook;
+    }
+    assert_eq!(last, "drop");
+}
+
+#[test]
+fn test_fsm_panic() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let mut cfg = Config::default();
+    cfg.pool_sizes[Priority::Normal] = 1;
+    let (router, mut system) = create_system(&cfg, ctrl_tx, ctrl_fsm);
+    system.spawn("test-fsm-panic".to_owned(), Builder::new());
+    let (failure_tx, failure_rx) = unbounded();
+    system.set_failure_handler(move |f| {
+        failure_tx.send((f.addr, f.panic_message().unwrap().to_owned(), f.restarted)).unwrap();
+    });
+    let (hook_tx, hook_rx) = unbounded();
+    for addr in 1..=2 {
+        let (sender, mut runner) = Runner::new(10);
+        if addr == 2 {
+            runner.lifecycle = Some(hook_tx.clone());
+        }
+        router.register(addr, BasicMailbox::new(sender, runner, Arc::default()));
+    }
+
+    // Both FSMs are in the same batch, only the one that panics is lost.
+    let (tx, rx) = unbounded();
+    router.send(1, Message::Callback(Box::new(|_: &Handler, _: &mut Runner| sleep(Duration::from_millis(50))))).unwrap();
+    router.send(2, Message::Callback(Box::new(|_: &Handler, _: &mut Runner| panic!("fsm 2 panics")))).unwrap();
+    let tx1 = tx.clone();
+    router.send(2, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| tx1.send(2).unwrap()))).unwrap();
+    let tx1 = tx.clone();
+    router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| tx1.send(1).unwrap()))).unwrap();
+    assert_eq!(failure_rx.recv_timeout(Duration::from_secs(3)), Ok((Some(2), "fsm 2 panics".to_owned(), false)));
+    // The failed FSM is dropped once the handler is done with it.
+    assert_eq!(hook_rx.recv_timeout(Duration::from_secs(3)), Ok("start"));
+    assert_eq!(hook_rx.recv_timeout(Duration::from_secs(3)), Ok("drop"));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(1));
+    assert!(router.send(2, Message::Loop(1)).is_err());
+    assert_eq!(rx.try_recv().ok(), None);
+
+    system.set_restart_factory(|_| Some(Runner::new(10)));
+    router.send(1, Message::Callback(Box::new(|_: &Handler, _: &mut Runner| panic!("fsm 1 panics")))).unwrap();
+    assert_eq!(failure_rx.recv_timeout(Duration::from_secs(3)), Ok((Some(1), "fsm 1 panics".to_owned(), true)));
+    router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| tx.send(1).unwrap()))).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(1));
+
+    let failed: u64 = system.metrics().pollers.iter().map(|p| p.metrics.failed).sum();
+    assert_eq!(failed, 2);
+    system.shutdown();
+}
+
+#[test]
+fn test_supervisor() {
+    use crate::tikv_batch::supervisor::{RestartStrategy, Supervisor};
+
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-supervisor".to_owned(), Builder::new());
+    let (failure_tx, failure_rx) = unbounded();
+    system.set_failure_handler(move |f| failure_tx.send((f.addr, f.restarted)).unwrap());
+
+    let starts: Arc<Vec<AtomicUsize>> = Arc::new((0..=5).map(|_| AtomicUsize::new(0)).collect());
+    let factory = |starts: &Arc<Vec<AtomicUsize>>| {
+        let starts = starts.clone();
+        move |addr: u64| {
+            starts[addr as usize].fetch_add(1, Ordering::SeqCst);
+            Some(Runner::new(10))
+        }
+    };
+    let start_counts = || starts.iter().skip(1).map(|c| c.load(Ordering::SeqCst)).collect::<Vec<_>>();
+    let (escalate_tx, escalate_rx) = unbounded();
+    let a = Supervisor::new("a", RestartStrategy::OneForOne)
+        .intensity(2, Duration::from_secs(60))
+        .child(1, factory(&starts))
+        .child(2, factory(&starts));
+    let b = Supervisor::new("b", RestartStrategy::RestForOne)
+        .child(3, factory(&starts))
+        .child(4, factory(&starts))
+        .child(5, factory(&starts));
+    system.supervise(
+        Supervisor::new("root", RestartStrategy::OneForOne)
+            .intensity(1, Duration::from_secs(60))
+            .supervisor(a)
+            .supervisor(b)
+            .on_escalate(move |name| escalate_tx.send(name.to_owned()).unwrap())
+    );
+    assert_eq!(start_counts(), vec![1, 1, 1, 1, 1]);
+
+    let fail = |addr: u64| {
+        router.send(addr, Message::Callback(Box::new(|_: &Handler, _: &mut Runner| panic!("fsm fails")))).unwrap();
+        failure_rx.recv_timeout(Duration::from_secs(3)).unwrap()
+    };
+    // Children added after the failed one are restarted too.
+    assert_eq!(fail(4), (Some(4), true));
+    assert_eq!(start_counts(), vec![1, 1, 1, 2, 2]);
+    assert_eq!(fail(1), (Some(1), true));
+    assert_eq!(fail(1), (Some(1), true));
+    assert_eq!(start_counts(), vec![3, 1, 1, 2, 2]);
+    // Supervisor "a" gives up, and it's restarted by the root.
+    assert_eq!(fail(2), (Some(2), true));
+    assert_eq!(start_counts(), vec![4, 2, 1, 2, 2]);
+    assert_eq!(fail(1), (Some(1), true));
+    assert_eq!(fail(1), (Some(1), true));
+    assert_eq!(start_counts(), vec![6, 2, 1, 2, 2]);
+    // Both "a" and the root give up, all FSMs in the tree are stopped.
+    assert_eq!(fail(1), (Some(1), false));
+    assert_eq!(escalate_rx.recv_timeout(Duration::from_secs(3)), Ok("root".to_owned()));
+    assert_eq!(start_counts(), vec![6, 2, 1, 2, 2]);
+    for addr in 1..=5 {
+        assert!(router.send(addr, Message::Loop(1)).is_err());
+    }
+
+    system.shutdown();
+}
+
+#[test]
+fn test_supervisor_restart_fails() {
+    use std::sync::atomic::AtomicBool;
+    use crate::tikv_batch::supervisor::{RestartStrategy, Supervisor};
+
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-supervisor-restart-fails".to_owned(), Builder::new());
+    let (failure_tx, failure_rx) = unbounded();
+    system.set_failure_handler(move |f| failure_tx.send((f.addr, f.restarted)).unwrap());
+
+    // The factory only creates the fsm the first time.
+    let started = AtomicBool::new(false);
+    system.supervise(
+        Supervisor::new("root", RestartStrategy::OneForOne)
+            .child(1, move |_| if started.swap(true, Ordering::SeqCst) { None } else { Some(Runner::new(10)) })
+    );
+    router.send(1, Message::Callback(Box::new(|_: &Handler, _: &mut Runner| panic!("fsm fails")))).unwrap();
+    assert_eq!(failure_rx.recv_timeout(Duration::from_secs(3)), Ok((Some(1), false)));
+    assert!(router.send(1, Message::Loop(1)).is_err());
+
+    system.shutdown();
+}
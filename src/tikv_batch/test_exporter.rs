@@ -0,0 +1,110 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use crate::tikv_batch::batch::create_system;
+use crate::tikv_batch::config::Config;
+use crate::tikv_batch::exporter::Exporter;
+use crate::tikv_batch::fsm::Priority;
+use crate::tikv_batch::mailbox::BasicMailbox;
+use crate::tikv_batch::mpsc::unbounded;
+use crate::tikv_batch::test_runner::{Builder, Handler, Message, Runner};
+
+fn scrape(addr: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+#[test]
+fn test_prometheus_exporter() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    // Label values are escaped.
+    system.spawn("test-\"exporter\"".to_owned(), Builder::new());
+
+    let (tx, rx) = unbounded();
+    let r = router.clone();
+    router.send_control(Message::Callback(Box::new(
+        move |_: &Handler, _: &mut Runner| {
+            let (sender, runner) = Runner::new(10);
+            r.register(1, BasicMailbox::new(sender, runner, r.state_cnt().clone()));
+            tx.send(()).unwrap();
+        }
+    ))).unwrap();
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    router.send(1, Message::Loop(10)).unwrap();
+
+    let exporter = Exporter::new("test", router.clone(), system.metrics_handle());
+    let server = exporter.serve("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().to_string();
+
+    let resp = scrape(&addr, "/metrics");
+    assert!(resp.starts_with("HTTP/1.1 200 OK"), "{}", resp);
+    assert!(resp.contains("test_router_alive_mailboxes 1\n"), "{}", resp);
+    assert!(resp.contains("test_router_fsm_states 2\n"), "{}", resp);
+    assert!(resp.contains("test_router_memory_bytes{type=\"alive\"}"), "{}", resp);
+    assert!(resp.contains("test_queue_length{priority=\"low\"} 0\n"), "{}", resp);
+    assert!(resp.contains("# TYPE test_poller_rounds_total counter\n"), "{}", resp);
+    // Poller names carry the tag of the test thread.
+    assert!(resp.contains(r#"test_poller_rounds_total{poller="test-\"exporter\"-0"#), "{}", resp);
+    assert!(resp.lines().any(|l| {
+        l.starts_with(r#"test_poller_batch_size_bucket{poller="test-\"exporter\"-low-0"#)
+            && l.ends_with(",priority=\"low\",le=\"+Inf\"} 0")
+    }), "{}", resp);
+
+    let resp = scrape(&addr, "/other");
+    assert!(resp.starts_with("HTTP/1.1 404 Not Found"), "{}", resp);
+    assert!(server.take_error().is_none());
+
+    drop(server);
+    assert!(TcpStream::connect(&addr).is_err());
+    system.shutdown();
+}
+
+#[test]
+fn test_exporter_after_scale_pool() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-exporter-scale".to_owned(), Builder::new());
+    let (sender, runner) = Runner::new(10);
+    router.register(1, BasicMailbox::new(sender, runner, router.state_cnt().clone()));
+
+    // Both pollers handle something before one of them retires, and the
+    // name of the retired one is used again when the pool grows.
+    let (tx, rx) = unbounded();
+    let (gate_tx, gate_rx) = unbounded::<()>();
+    let tx_ = tx.clone();
+    router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        tx_.send(()).unwrap();
+        let _ = gate_rx.recv_timeout(Duration::from_secs(10));
+    }))).unwrap();
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    router.send_control(Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        tx.send(()).unwrap();
+    }))).unwrap();
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    gate_tx.send(()).unwrap();
+    system.scale_pool(Priority::Normal, 1);
+    system.scale_pool(Priority::Normal, 2);
+
+    let exporter = Exporter::new("test", router.clone(), system.metrics_handle());
+    let server = exporter.serve("127.0.0.1:0").unwrap();
+    let resp = scrape(&server.local_addr().to_string(), "/metrics");
+    let body = &resp[resp.find("\r\n\r\n").unwrap() + 4..];
+
+    // Every series is exported once.
+    let mut series = HashSet::new();
+    for line in body.lines().filter(|l| !l.starts_with('#')) {
+        let (name, _) = line.rsplit_once(' ').unwrap();
+        assert!(series.insert(name), "duplicate series {}", name);
+    }
+    let pollers = body.lines().filter(|l| l.starts_with("test_poller_rounds_total{")).count();
+    assert_eq!(pollers, 4);
+    assert!(body.contains("test_poller_rounds_total{poller=\"retired\",priority=\"normal\"} "), "{}", body);
+
+    drop(server);
+    system.shutdown();
+}
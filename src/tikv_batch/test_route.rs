@@ -7,6 +7,14 @@ use crate::tikv_batch::config::Config;
 use crossbeam::channel::{SendError, TrySendError,TryRecvError,RecvTimeoutError};
 use crate::tikv_batch::mailbox::BasicMailbox;
 use std::time::Duration;
+use futures::executor::block_on;
+use crate::tikv_batch::ask::{AskError, Reply};
+use crate::tikv_batch::batch::PollHandler;
+use crate::tikv_batch::fsm::Priority;
+use crate::tikv_batch::router::{BroadcastMode, DeadLetterReason};
+use crate::tikv_batch::batch::BatchRouter;
+use std::thread;
+use std::time::Instant;
 
 fn counter_closure(counter: &Arc<AtomicUsize>) -> Message {
     let c = counter.clone();
@@ -128,4 +136,796 @@ fn test_basic() {
 
 
 
-}
\ No newline at end of file
+}
+#[test]
+fn test_latency() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-latency".to_owned(), Builder::new());
+
+    let (sender, runner) = Runner::with_latency(10);
+    router.register(1, BasicMailbox::new(sender, runner, Arc::default()));
+    let (sender, runner) = Runner::new(10);
+    router.register(2, BasicMailbox::new(sender, runner, Arc::default()));
+
+    router.send(1, Message::Callback(Box::new(|_: &Handler, _: &mut Runner| {
+        std::thread::sleep(Duration::from_millis(20));
+    }))).unwrap();
+    let (tx, rx) = unbounded();
+    for _ in 0..3 {
+        let tx = tx.clone();
+        router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+            tx.send(()).unwrap();
+        }))).unwrap();
+    }
+    for _ in 0..3 {
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+
+    let latency = router.latency_of(1).unwrap();
+    assert_eq!(latency.count(), 4);
+    assert!(latency.max() >= 10_000, "{:?}", latency);
+    assert!(router.latency_of(2).is_none());
+    assert_eq!(router.latency().count(), 4);
+    assert_eq!(router.slow_mailboxes(0.99, Duration::from_millis(5)), vec![1]);
+    assert!(router.slow_mailboxes(0.99, Duration::from_secs(5)).is_empty());
+
+    system.shutdown();
+}
+
+#[test]
+fn test_send_async() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-send-async".to_owned(), Builder::new());
+
+    // Missing mailbox should fail at once.
+    assert!(block_on(router.send_async(1, noop())).is_err());
+
+    let (sender, runner) = Runner::new(10);
+    router.register(1, BasicMailbox::new(sender, runner, Arc::default()));
+
+    let block_fsm = || {
+        let (tx, rx) = unbounded();
+        router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+            let _ = rx.recv_timeout(Duration::from_secs(10));
+        }))).unwrap();
+        while router.send(1, noop()).is_ok() {}
+        tx
+    };
+
+    // A pending send should be finished once the fsm catches up.
+    let unblock = block_fsm();
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (res_tx, res_rx) = unbounded();
+    let f = router.send_async(1, counter_closure(&counter));
+    let res_tx_ = res_tx.clone();
+    std::thread::spawn(move || res_tx_.send(block_on(f).is_ok()).unwrap());
+    assert_eq!(res_rx.recv_timeout(Duration::from_millis(100)), Err(RecvTimeoutError::Timeout));
+    unblock.send(()).unwrap();
+    assert_eq!(res_rx.recv_timeout(Duration::from_secs(3)), Ok(true));
+
+    let mailbox = router.mailbox(1).unwrap();
+    let (tx, rx) = unbounded();
+    block_on(mailbox.send_async(Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        tx.send(()).unwrap();
+    })))).unwrap();
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!(counter.load(Ordering::SeqCst), 1);
+
+    // A pending send should fail once the mailbox is closed.
+    let unblock = block_fsm();
+    let f = mailbox.send_async(unreachable());
+    std::thread::spawn(move || res_tx.send(block_on(f).is_ok()).unwrap());
+    assert_eq!(res_rx.recv_timeout(Duration::from_millis(100)), Err(RecvTimeoutError::Timeout));
+    router.close(1);
+    assert_eq!(res_rx.recv_timeout(Duration::from_secs(3)), Ok(false));
+    drop(unblock);
+
+    system.shutdown();
+}
+
+#[test]
+fn test_ask() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-ask".to_owned(), Builder::new());
+
+    // Missing mailbox should fail at once.
+    assert!(router.ask(1, |reply: Reply<usize>| Message::Callback(Box::new(move |_, _| {
+        reply.send(1);
+    }))).is_err());
+
+    let (sender, runner) = Runner::new(10);
+    router.register(1, BasicMailbox::new(sender, runner, Arc::default()));
+
+    let handle = router.ask(1, |reply| Message::Callback(Box::new(move |h: &Handler, _: &mut Runner| {
+        reply.send(h.get_priority());
+    }))).unwrap();
+    assert_eq!(handle.wait(Duration::from_secs(3)), Ok(Priority::Normal));
+
+    let handle = router.ask_control(|reply| Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        reply.send(2);
+    }))).unwrap();
+    assert_eq!(block_on(handle), Ok(2));
+
+    let handle = router.mailbox(1).unwrap().ask(|reply| Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        reply.send(3);
+    }))).unwrap();
+    assert_eq!(block_on(handle), Ok(3));
+
+    // Dropping the reply should wake up the asker.
+    let handle = router.ask(1, |reply: Reply<usize>| Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        drop(reply);
+    }))).unwrap();
+    assert_eq!(block_on(handle), Err(AskError::Dropped));
+
+    let (tx, rx) = unbounded();
+    router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        let _ = rx.recv_timeout(Duration::from_secs(10));
+    }))).unwrap();
+    let handle = router.ask(1, |reply| Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        reply.send(4);
+    }))).unwrap();
+    assert_eq!(handle.wait(Duration::from_millis(50)), Err(AskError::Timeout));
+    tx.send(()).unwrap();
+    assert_eq!(handle.wait(Duration::from_secs(3)), Ok(4));
+
+    system.shutdown();
+}
+
+#[test]
+fn test_ask_with_timeout() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-ask-with-timeout".to_owned(), Builder::new());
+    let (sender, runner) = Runner::new(10);
+    router.register(1, BasicMailbox::new(sender, runner, Arc::default()));
+
+    // The fsm is blocked, so the future is only woken up by its deadline.
+    let (tx, rx) = unbounded();
+    router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        let _ = rx.recv_timeout(Duration::from_secs(10));
+    }))).unwrap();
+    let ask = |n: usize| router.ask(1, move |reply| Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        reply.send(n);
+    }))).unwrap();
+    let timer = Instant::now();
+    let late = ask(1).with_timeout(Duration::from_millis(100));
+    let early = ask(2).with_timeout(Duration::from_millis(50));
+    assert_eq!(block_on(early), Err(AskError::Timeout));
+    assert_eq!(block_on(late), Err(AskError::Timeout));
+    let elapsed = timer.elapsed();
+    assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_secs(3), "{:?}", elapsed);
+
+    tx.send(()).unwrap();
+    assert_eq!(block_on(ask(3).with_timeout(Duration::from_secs(3))), Ok(3));
+
+    system.shutdown();
+}
+
+#[test]
+fn test_dead_letter() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-dead-letter".to_owned(), Builder::new());
+
+    let (tx, rx) = unbounded();
+    router.set_dead_letter_sink(move |addr, _, reason| tx.send((addr, reason)).unwrap());
+
+    router.force_send(1, unreachable()).unwrap();
+    assert_eq!(rx.try_recv(), Ok((1, DeadLetterReason::NotExist)));
+    router.send_or_dead_letter(1, unreachable()).unwrap();
+    assert_eq!(rx.try_recv(), Ok((1, DeadLetterReason::NotExist)));
+    // Plain send still gives the message back.
+    assert!(router.send(1, unreachable()).is_err());
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+    // A mailbox whose receiver is dropped is disconnected.
+    let (sender, _) = Runner::new(10);
+    let (_, runner) = Runner::new(10);
+    router.register(2, BasicMailbox::new(sender, runner, Arc::default()));
+    router.force_send(2, unreachable()).unwrap();
+    assert_eq!(rx.try_recv(), Ok((2, DeadLetterReason::Disconnected)));
+    router.broadcast_normal(unreachable);
+    assert_eq!(rx.try_recv(), Ok((2, DeadLetterReason::Disconnected)));
+    router.close(2);
+
+    let (sender, runner) = Runner::new(10);
+    router.register(3, BasicMailbox::new(sender, runner, Arc::default()));
+    let (unblock_tx, unblock_rx) = unbounded();
+    router.send(3, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        let _ = unblock_rx.recv_timeout(Duration::from_secs(10));
+    }))).unwrap();
+    while router.send(3, noop()).is_ok() {}
+    router.send_or_dead_letter(3, noop()).unwrap();
+    assert_eq!(rx.try_recv(), Ok((3, DeadLetterReason::Full)));
+    // force_send ignores the capacity limit.
+    router.force_send(3, noop()).unwrap();
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    unblock_tx.send(()).unwrap();
+
+    router.clear_dead_letter_sink();
+    assert!(router.force_send(1, unreachable()).is_err());
+    assert!(router.send_or_dead_letter(1, unreachable()).is_err());
+
+    system.shutdown();
+}
+
+#[test]
+fn test_fsm_factory() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-fsm-factory".to_owned(), Builder::new());
+
+    let created = Arc::new(AtomicUsize::new(0));
+    let created_ = created.clone();
+    router.set_fsm_factory(move |addr: u64| {
+        if addr == 2 {
+            return None;
+        }
+        created_.fetch_add(1, Ordering::SeqCst);
+        Some(Runner::new(10))
+    });
+
+    // Concurrent senders of the same address should create the fsm only once.
+    let counter = Arc::new(AtomicUsize::new(0));
+    let handles: Vec<_> = (0..4).map(|_| {
+        let r = router.clone();
+        let counter = counter.clone();
+        thread::spawn(move || {
+            for _ in 0..10 {
+                r.force_send(1, counter_closure(&counter)).unwrap();
+            }
+        })
+    }).collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+    let (tx, rx) = unbounded();
+    router.force_send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        tx.send(()).unwrap();
+    }))).unwrap();
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!(counter.load(Ordering::SeqCst), 40);
+    assert_eq!(created.load(Ordering::SeqCst), 1);
+    assert_eq!(router.alive_cnt().load(Ordering::SeqCst), 1);
+
+    // The factory may refuse to create an fsm.
+    assert!(router.send(2, unreachable()).is_err());
+    assert!(router.mailbox(2).is_none());
+
+    router.clear_fsm_factory();
+    assert!(router.send(3, unreachable()).is_err());
+    assert_eq!(created.load(Ordering::SeqCst), 1);
+
+    system.shutdown();
+}
+
+#[test]
+fn test_shared_router() {
+    fn assert_sync<T: Send + Sync>() {}
+    assert_sync::<BatchRouter<Runner, Runner>>();
+
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-shared-router".to_owned(), Builder::new());
+    let router = Arc::new(router);
+
+    // Addresses are more than the shards of the cache, so that every shard is
+    // hit by multiple addresses.
+    let addrs = 64;
+    for addr in 0..addrs {
+        let (sender, runner) = Runner::new(10);
+        router.register(addr, BasicMailbox::new(sender, runner, Arc::default()));
+    }
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let sent = Arc::new(AtomicUsize::new(0));
+    let handles: Vec<_> = (0..8).map(|i| {
+        let router = router.clone();
+        let counter = counter.clone();
+        let sent = sent.clone();
+        thread::spawn(move || {
+            for j in 0..2000 {
+                let addr = (i * 7 + j) % addrs;
+                if router.force_send(addr, counter_closure(&counter)).is_ok() {
+                    sent.fetch_add(1, Ordering::SeqCst);
+                }
+                if j % 100 == 0 {
+                    router.clear_cache();
+                }
+            }
+        })
+    }).collect();
+
+    // Closing and registering mailboxes concurrently should never make a send
+    // go to a stale mailbox.
+    for _ in 0..20 {
+        router.close(addrs - 1);
+        let (sender, runner) = Runner::new(10);
+        router.register(addrs - 1, BasicMailbox::new(sender, runner, Arc::default()));
+    }
+    for h in handles {
+        h.join().unwrap();
+    }
+
+    let (tx, rx) = unbounded();
+    for addr in 0..addrs {
+        let tx = tx.clone();
+        router.force_send(addr, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+            tx.send(()).unwrap();
+        }))).unwrap();
+    }
+    for _ in 0..addrs {
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+    // Messages sent to closed mailboxes are dropped with them.
+    let handled = counter.load(Ordering::SeqCst);
+    let sent = sent.load(Ordering::SeqCst);
+    assert!(handled <= sent, "{} {}", handled, sent);
+    assert!(sent - handled <= 8 * 2000 / addrs as usize + 8, "{} {}", handled, sent);
+
+    system.shutdown();
+}
+
+#[test]
+fn test_send_in_on_start() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-send-in-on-start".to_owned(), Builder::new());
+
+    // The fsm is started by the first send, and it sends to itself through
+    // the same router, whose cache is shared, before that send returns.
+    let router = Arc::new(router);
+    let (tx, rx) = unbounded();
+    let (sender, mut runner) = Runner::new(10);
+    let (router_, tx_) = (Arc::downgrade(&router), tx.clone());
+    runner.start_hook = Some(Box::new(move || {
+        router_.upgrade().unwrap().send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+            tx_.send("from on_start").unwrap();
+        }))).unwrap();
+    }));
+    router.register(1, BasicMailbox::new(sender, runner, Arc::default()));
+    let router_ = router.clone();
+    thread::spawn(move || {
+        router_.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+            tx.send("first").unwrap();
+        }))).unwrap();
+    });
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok("first"));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok("from on_start"));
+
+    router.close(1);
+    system.shutdown();
+}
+
+#[test]
+fn test_send_blocking() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, _system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    // No poller is started, so mailboxes are never drained. Both addresses
+    // are in the same cache shard.
+    for addr in &[1, 17] {
+        let (tx, runner) = Runner::blocking(1, Duration::from_millis(200));
+        router.register(*addr, BasicMailbox::new(tx, runner, Arc::default()));
+    }
+    router.send(1, noop()).unwrap();
+
+    // `send` never waits for room.
+    let timer = Instant::now();
+    assert!(matches!(router.send(1, noop()), Err(TrySendError::Full(_))));
+    assert!(timer.elapsed() < Duration::from_millis(200));
+
+    // A waiting sender doesn't block other addresses.
+    let router = Arc::new(router);
+    let r = router.clone();
+    let handle = thread::spawn(move || {
+        let timer = Instant::now();
+        let res = r.send_blocking(1, noop());
+        (matches!(res, Err(TrySendError::Full(_))), timer.elapsed())
+    });
+    thread::sleep(Duration::from_millis(20));
+    let timer = Instant::now();
+    router.send(17, noop()).unwrap();
+    assert!(timer.elapsed() < Duration::from_millis(100));
+    let (full, elapsed) = handle.join().unwrap();
+    assert!(full);
+    assert!(elapsed >= Duration::from_millis(200));
+
+    assert!(matches!(router.send_blocking(2, noop()), Err(TrySendError::Disconnected(_))));
+}
+
+#[test]
+fn test_router_shards() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let cfg = Config {
+        router_shards: 4,
+        ..Config::default()
+    };
+    let (router, mut system) = create_system(&cfg, ctrl_tx, ctrl_fsm);
+    system.spawn("test-router-shards".to_owned(), Builder::new());
+
+    let state_cnt = router.state_cnt().clone();
+    for addr in 0..10 {
+        let (sender, runner) = Runner::new(10);
+        router.register(addr, BasicMailbox::new(sender, runner, state_cnt.clone()));
+    }
+    let mailboxes = (5..15).map(|addr| {
+        let (sender, runner) = Runner::new(10);
+        (addr, BasicMailbox::new(sender, runner, state_cnt.clone()))
+    }).collect();
+    router.register_all(mailboxes);
+    assert_eq!(router.alive_cnt().load(Ordering::SeqCst), 15);
+    router.close(3);
+    router.close(3);
+    assert_eq!(router.alive_cnt().load(Ordering::SeqCst), 14);
+
+    // Every mailbox is reachable no matter which shard it's in.
+    let (tx, rx) = unbounded();
+    router.broadcast_normal(|| {
+        let tx = tx.clone();
+        Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| tx.send(()).unwrap()))
+    });
+    for _ in 0..14 {
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    // 14 alive mailboxes and the control fsm.
+    assert_eq!(state_cnt.load(Ordering::SeqCst), 15);
+    assert_eq!(router.trace().leak, 0);
+
+    system.shutdown();
+    assert_eq!(router.alive_cnt().load(Ordering::SeqCst), 0);
+}
+
+/// Compares the throughput of the address book with a single lock, which is
+/// how it used to be, and with the default shards. Run it with
+/// `cargo test --release bench_router_shards -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_router_shards() {
+    let threads = 8;
+    let rounds = 20000;
+    for shards in &[1, Config::default().router_shards] {
+        let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+        let cfg = Config {
+            router_shards: *shards,
+            ..Config::default()
+        };
+        let (router, _system) = create_system(&cfg, ctrl_tx, ctrl_fsm);
+        let router = Arc::new(router);
+
+        let handles: Vec<_> = (0..threads).map(|i| {
+            let router = router.clone();
+            let mailboxes: Vec<_> = (0..rounds).map(|_| {
+                let (sender, runner) = Runner::new(10);
+                BasicMailbox::new(sender, runner, Arc::default())
+            }).collect();
+            thread::spawn(move || {
+                let timer = Instant::now();
+                // Every operation takes the lock of the address book: sends to
+                // missing addresses, registering and closing mailboxes.
+                for (j, mailbox) in mailboxes.into_iter().enumerate() {
+                    let addr = (i * rounds + j) as u64;
+                    assert!(router.send(addr, noop()).is_err());
+                    router.register(addr, mailbox);
+                    router.close(addr);
+                }
+                timer.elapsed()
+            })
+        }).collect();
+        let elapsed = handles.into_iter().map(|h| h.join().unwrap()).max().unwrap();
+        let ops = threads * rounds * 3;
+        println!(
+            "shards: {:>3}, {} ops in {:?}, {:.0} ops/s",
+            shards,
+            ops,
+            elapsed,
+            ops as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
+
+#[test]
+fn test_batch_register_and_close() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-batch-register".to_owned(), Builder::new());
+
+    let new_mailboxes = |addrs: std::ops::Range<u64>| -> Vec<_> {
+        addrs.map(|addr| {
+            let (sender, runner) = Runner::new(10);
+            (addr, BasicMailbox::new(sender, runner, Arc::default()))
+        }).collect()
+    };
+    assert!(router.register_all(new_mailboxes(0..4)).is_empty());
+    let replaced = router.register_all_atomic(new_mailboxes(2..6));
+    let mut replaced: Vec<_> = replaced.into_iter().map(|(addr, _)| addr).collect();
+    replaced.sort_unstable();
+    assert_eq!(replaced, vec![2, 3]);
+    assert_eq!(router.alive_cnt().load(Ordering::SeqCst), 6);
+
+    let removed = router.close_all(&[0, 1, 10]);
+    assert_eq!(removed.len(), 2);
+    let removed = router.close_all_atomic(&[2, 3, 4]);
+    assert_eq!(removed.len(), 3);
+    assert_eq!(router.alive_cnt().load(Ordering::SeqCst), 1);
+    for addr in 0..5 {
+        assert!(router.send(addr, unreachable()).is_err());
+    }
+    router.send(5, noop()).unwrap();
+
+    // Once the first mailbox of a group is visible, so are the others.
+    let router = Arc::new(router);
+    let r = router.clone();
+    let groups = 200;
+    let handle = thread::spawn(move || {
+        for group in 1..=groups {
+            r.register_all_atomic(new_mailboxes(group * 100..group * 100 + 32));
+        }
+    });
+    let mut checked = 0;
+    while checked < groups {
+        for group in 1..=groups {
+            if router.mailbox(group * 100).is_some() {
+                for addr in group * 100 + 1..group * 100 + 32 {
+                    assert!(router.mailbox(addr).is_some(), "{} is not visible", addr);
+                }
+                checked = checked.max(group);
+            }
+        }
+    }
+    handle.join().unwrap();
+    assert_eq!(router.alive_cnt().load(Ordering::SeqCst), 1 + groups as usize * 32);
+
+    system.shutdown();
+}
+
+#[test]
+fn test_broadcast_filtered() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-broadcast-filtered".to_owned(), Builder::new());
+
+    for addr in 1..=4 {
+        let (sender, runner) = Runner::new(10);
+        router.register(addr, BasicMailbox::new(sender, runner, Arc::default()));
+    }
+    // Mailbox 5 is disconnected and mailbox 6 is full.
+    let (sender, _) = Runner::new(10);
+    router.register(5, BasicMailbox::new(sender, Runner::new(10).1, Arc::default()));
+    let (sender, runner) = Runner::new(10);
+    router.register(6, BasicMailbox::new(sender, runner, Arc::default()));
+    let (unblock_tx, unblock_rx) = unbounded();
+    router.send(6, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        let _ = unblock_rx.recv_timeout(Duration::from_secs(10));
+    }))).unwrap();
+    // The mailbox in the cache and the one in the address book check the
+    // capacity independently, fill it through both of them.
+    while router.send(6, noop()).is_ok() {}
+    while router.broadcast_filtered(BroadcastMode::Locked, |addr| addr == 6, noop).sent > 0 {}
+
+    let (dead_tx, dead_rx) = unbounded();
+    router.set_dead_letter_sink(move |addr, _, reason| dead_tx.send((addr, reason)).unwrap());
+
+    let (tx, rx) = unbounded();
+    let ping = || {
+        let tx = tx.clone();
+        Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| tx.send(()).unwrap()))
+    };
+    let report = router.broadcast_filtered(BroadcastMode::Locked, |addr| addr % 2 == 0, ping);
+    assert_eq!(report.sent, 2);
+    assert_eq!(report.full(), vec![6]);
+    assert!(report.disconnected().is_empty());
+    assert_eq!(dead_rx.try_recv(), Ok((6, DeadLetterReason::Full)));
+    for _ in 0..2 {
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+
+    // No lock is held during sending, so the generator can access the router.
+    let r = router.clone();
+    let mut report = router.broadcast_filtered(BroadcastMode::Snapshot, |_| true, || {
+        r.close(7);
+        ping()
+    });
+    report.failed.sort_unstable_by_key(|(addr, _)| *addr);
+    assert_eq!(report.sent, 4);
+    assert_eq!(report.failed, vec![(5, DeadLetterReason::Disconnected), (6, DeadLetterReason::Full)]);
+    for _ in 0..4 {
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+
+    let report = router.broadcast_to(&[1, 5, 6, 9], ping);
+    assert_eq!(report.sent, 1);
+    assert_eq!(report.failed, vec![
+        (5, DeadLetterReason::Disconnected),
+        (6, DeadLetterReason::Full),
+        (9, DeadLetterReason::NotExist),
+    ]);
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+    unblock_tx.send(()).unwrap();
+    system.shutdown();
+}
+
+#[test]
+fn test_mailbox_lanes() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-mailbox-lanes".to_owned(), Builder::new());
+
+    let (sender, runner) = Runner::with_lanes(100, [8, 4, 1]);
+    let mailbox = BasicMailbox::new(sender, runner, Arc::default());
+    router.register(1, mailbox.clone());
+    let (unblock_tx, unblock_rx) = unbounded();
+    router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        let _ = unblock_rx.recv_timeout(Duration::from_secs(10));
+    }))).unwrap();
+    while !mailbox.is_empty() {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    for _ in 0..40 {
+        router.send(1, Message::Loop(100)).unwrap();
+    }
+    // The callback goes to the urgent lane, so it's handled before the loops.
+    let (tx, rx) = unbounded();
+    router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        tx.send(mailbox.len()).unwrap();
+    }))).unwrap();
+    unblock_tx.send(()).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(40));
+
+    system.shutdown();
+}
+
+#[test]
+fn test_send_many() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let (router, mut system) = create_system(&Config::default(), ctrl_tx, ctrl_fsm);
+    system.spawn("test-send-many".to_owned(), Builder::new());
+
+    let (sender, runner) = Runner::new(5);
+    let mailbox = BasicMailbox::new(sender, runner, Arc::default());
+    router.register(1, mailbox.clone());
+    let (unblock_tx, unblock_rx) = unbounded();
+    router.send(1, Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| {
+        let _ = unblock_rx.recv_timeout(Duration::from_secs(10));
+    }))).unwrap();
+    while !mailbox.is_empty() {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    // Messages are handled in order, and it stops once the mailbox is full.
+    let (tx, rx) = unbounded();
+    let mut msgs = (0..8).map(|i| {
+        let tx = tx.clone();
+        Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| tx.send(i).unwrap()))
+    });
+    let (sent, res) = router.send_many(1, &mut msgs);
+    // The capacity is loosely bounded, so a few more messages may be sent.
+    assert!((5..8).contains(&sent), "{}", sent);
+    assert!(matches!(res, Err(TrySendError::Full(_))));
+    assert_eq!(msgs.len(), 7 - sent);
+    unblock_tx.send(()).unwrap();
+    for i in 0..sent {
+        assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(i));
+    }
+    let (rest, res) = router.send_many(1, &mut msgs);
+    assert_eq!((rest, res.is_ok()), (7 - sent, true));
+    for i in sent + 1..8 {
+        assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(i));
+    }
+    let (sent, res) = router.send_many(2, &mut (0..3).map(|_| unreachable()));
+    assert_eq!(sent, 0);
+    assert!(matches!(res, Err(TrySendError::Disconnected(_))));
+
+    // Mailbox 3 is disconnected.
+    let (sender, _) = Runner::new(10);
+    router.register(3, BasicMailbox::new(sender, Runner::new(10).1, Arc::default()));
+    let (dead_tx, dead_rx) = unbounded();
+    router.set_dead_letter_sink(move |addr, _, reason| dead_tx.send((addr, reason)).unwrap());
+    let ping = |i: usize| {
+        let tx = tx.clone();
+        Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| tx.send(i).unwrap()))
+    };
+    let report = router.send_batch(vec![
+        (1, ping(0)),
+        (3, unreachable()),
+        (2, unreachable()),
+        (1, ping(1)),
+        (3, unreachable()),
+        (1, ping(2)),
+    ]);
+    assert_eq!(report.sent, 3);
+    assert_eq!(report.failed, vec![
+        (3, DeadLetterReason::Disconnected),
+        (3, DeadLetterReason::Disconnected),
+        (2, DeadLetterReason::NotExist),
+    ]);
+    for i in 0..3 {
+        assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(i));
+    }
+    assert_eq!(dead_rx.try_recv(), Ok((3, DeadLetterReason::Disconnected)));
+    assert_eq!(dead_rx.try_recv(), Ok((3, DeadLetterReason::Disconnected)));
+    assert_eq!(dead_rx.try_recv(), Ok((2, DeadLetterReason::NotExist)));
+
+    system.shutdown();
+}
+
+#[test]
+fn test_timers() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let cfg = Config { timer_tick: Duration::from_millis(5), timer_wheel_size: 8, ..Config::default() };
+    let (router, mut system) = create_system(&cfg, ctrl_tx, ctrl_fsm);
+    system.spawn("test-timers".to_owned(), Builder::new());
+    for addr in 1..=2 {
+        let (sender, runner) = Runner::new(10);
+        router.register(addr, BasicMailbox::new(sender, runner, Arc::default()));
+    }
+
+    let (tx, rx) = unbounded();
+    let ping = move |i: usize| {
+        let tx = tx.clone();
+        Message::Callback(Box::new(move |_: &Handler, _: &mut Runner| tx.send(i).unwrap()))
+    };
+    // Delays are longer than a round of the wheel.
+    let start = Instant::now();
+    router.schedule_after(1, Duration::from_millis(60), ping(2));
+    router.schedule_after(2, Duration::from_millis(20), ping(1));
+    let cancelled = router.schedule_after(1, Duration::from_millis(40), ping(0));
+    assert!(cancelled.cancel());
+    assert!(!cancelled.cancel());
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(1));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(2));
+    assert!(start.elapsed() >= Duration::from_millis(60));
+    assert_eq!(router.pending_timers(), 0);
+
+    let ping = move || ping(3);
+    let tick = router.schedule_every(1, Duration::from_millis(10), ping.clone());
+    for _ in 0..3 {
+        assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(3));
+    }
+    assert!(tick.cancel());
+    // Timers are cleaned up when the mailbox is closed.
+    let tick = router.schedule_every(2, Duration::from_millis(10), ping.clone());
+    router.schedule_after(2, Duration::from_secs(10), ping());
+    assert_eq!(router.pending_timers(), 2);
+    router.close(2);
+    assert_eq!(router.pending_timers(), 0);
+    assert!(!tick.cancel());
+    thread::sleep(Duration::from_millis(30));
+    while rx.try_recv().is_ok() {}
+    thread::sleep(Duration::from_millis(30));
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+    system.shutdown();
+    let tick = router.schedule_every(1, Duration::from_millis(10), ping);
+    assert_eq!(router.pending_timers(), 0);
+    assert!(!tick.cancel());
+}
+
+#[test]
+fn test_timer_dropped_with_router() {
+    let (ctrl_tx, ctrl_fsm) = Runner::new(10);
+    let cfg = Config { timer_tick: Duration::from_millis(5), ..Config::default() };
+    let (router, system) = create_system::<Runner, Runner>(&cfg, ctrl_tx, ctrl_fsm);
+    let pending = Arc::new(());
+    let p = pending.clone();
+    router.schedule_every(1, Duration::from_secs(10), move || {
+        let _ = &p;
+        Message::Loop(0)
+    });
+    // The timer thread is started, but it doesn't keep the router alive, so
+    // the timer and its pending timers are dropped without a shutdown.
+    drop(system);
+    drop(router);
+    let timer = Instant::now();
+    while Arc::strong_count(&pending) > 1 {
+        assert!(timer.elapsed() < Duration::from_secs(3));
+        thread::sleep(Duration::from_millis(10));
+    }
+}
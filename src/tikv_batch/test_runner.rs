@@ -1,9 +1,11 @@
 use std::sync::{Arc, Mutex};
 use crate::tikv_batch::fsm::{Priority, Fsm};
-use crate::tikv_batch::mpsc::{Receiver, Sender, LooseBoundedSender, loose_bounded};
+use crate::tikv_batch::mpsc::{Receiver, Sender, LooseBoundedSender, Lane, loose_bounded, loose_bounded_with_latency, loose_bounded_with_lanes, blocking_bounded};
 use crate::tikv_batch::mailbox::BasicMailbox;
 use std::borrow::Cow;
+use std::time::Duration;
 use crate::tikv_batch::batch::{PollHandler, HandlerBuilder};
+use crate::tikv_batch::reschedule::BatchStats;
 use derive_more::{Add, AddAssign};
 
 
@@ -13,6 +15,10 @@ pub struct Runner{
     recv:Receiver<Message>,
     mailbox:Option<BasicMailbox<Runner>>,
     pub sender:Option<Sender<()>>,
+    /// Receives the names of lifecycle hooks that are called.
+    pub lifecycle:Option<Sender<&'static str>>,
+    /// Called once by `on_start`.
+    pub start_hook:Option<Box<dyn FnOnce()+Send>>,
     /// Result of the calculation triggered by `Message::Loop`.
     /// Stores it inside `Runner` to avoid accidental optimization.
     res:usize,
@@ -37,17 +43,57 @@ impl Fsm for Runner{
     fn get_priority(&self) -> Priority {
         self.priority
     }
+
+    fn on_start(&mut self) {
+        self.record("start");
+        if let Some(hook)=self.start_hook.take(){
+            hook();
+        }
+    }
+
+    fn on_idle(&mut self) {
+        self.record("idle");
+    }
+
+    fn on_stop(&mut self) {
+        self.record("stop");
+    }
+
+    fn on_drop(&mut self) {
+        self.record("drop");
+    }
 }
 
 impl Runner{
     pub fn new(cap:usize) -> (LooseBoundedSender<Message>,Box<Runner>){
-        let (tx,rx)=loose_bounded(cap);
+        Runner::with_channel(loose_bounded(cap))
+    }
+
+    /// Create a runner whose mailbox tracks queue wait time.
+    pub fn with_latency(cap:usize) -> (LooseBoundedSender<Message>,Box<Runner>){
+        Runner::with_channel(loose_bounded_with_latency(cap))
+    }
+
+    /// Create a runner whose mailbox puts callbacks in the urgent lane
+    /// and loops in the background lane.
+    pub fn with_lanes(cap:usize,weights:[usize;3]) -> (LooseBoundedSender<Message>,Box<Runner>){
+        Runner::with_channel(loose_bounded_with_lanes(cap,weights,Message::lane))
+    }
+
+    /// Create a runner whose senders wait up to `timeout` for room.
+    pub fn blocking(cap:usize,timeout:Duration) -> (LooseBoundedSender<Message>,Box<Runner>){
+        Runner::with_channel(blocking_bounded(cap,timeout))
+    }
+
+    fn with_channel((tx,rx):(LooseBoundedSender<Message>,Receiver<Message>)) -> (LooseBoundedSender<Message>,Box<Runner>){
         let fsm=Box::new(
             Runner{
                 is_stopped:false,
                 recv:rx,
                 mailbox:None,
                 sender:None,
+                lifecycle:None,
+                start_hook:None,
                 res:0,
                 priority:Priority::Normal,
             }
@@ -59,6 +105,16 @@ impl Runner{
     pub fn set_priority(&mut self,priority:Priority){
         self.priority=priority;
     }
+
+    pub fn stop(&mut self){
+        self.is_stopped=true;
+    }
+
+    fn record(&self,hook:&'static str){
+        if let Some(ref tx)=self.lifecycle{
+            let _=tx.send(hook);
+        }
+    }
 }
 
 //endregion
@@ -73,13 +129,20 @@ pub struct HandleMetrics{
 pub struct Handler{
     local:HandleMetrics,
     metrics:Arc<Mutex<HandleMetrics>>,
-    priority:Priority
+    priority:Priority,
+    /// Count of messages handled by the last `handle` call.
+    handled:usize
 }
 
 impl Handler{
     fn handle(&mut self,r:&mut Runner) ->Option<usize>{
+        self.handled=0;
         for _ in 0..16{
-            match r.recv.try_recv(){
+            let msg=r.recv.try_recv();
+            if msg.is_ok(){
+                self.handled+=1;
+            }
+            match msg{
                 Ok(Message::Loop(count))=>{
                     for _ in 0..count{
                         // Some calculation to represent a CPU consuming work
@@ -96,7 +159,7 @@ impl Handler{
 }
 
 impl PollHandler<Runner,Runner> for Handler{
-    fn begin(&mut self, batch_size: usize) {
+    fn begin(&mut self, batch_size: usize, _time_budget: Option<Duration>) {
         self.local.begin+=1;
     }
 
@@ -105,9 +168,11 @@ impl PollHandler<Runner,Runner> for Handler{
         self.handle(control)
     }
 
-    fn handle_normal(&mut self, normal: &mut Runner) -> Option<usize> {
+    fn handle_normal(&mut self, normal: &mut Runner, stats: &mut BatchStats) -> Option<usize> {
         self.local.normal+=1;
-        self.handle(normal)
+        let len=self.handle(normal);
+        stats.messages+=self.handled;
+        len
     }
 
     fn end(&mut self, batch: &mut [Box<Runner>]) {
@@ -115,6 +180,10 @@ impl PollHandler<Runner,Runner> for Handler{
         *c+=self.local;
         self.local=HandleMetrics::default();
     }
+
+    fn get_priority(&self) -> Priority {
+        self.priority
+    }
 }
 
 //endregion
@@ -138,7 +207,8 @@ impl HandlerBuilder<Runner,Runner> for Builder{
         Handler{
             local:HandleMetrics::default(),
             metrics:self.metrics.clone(),
-            priority
+            priority,
+            handled:0
         }
     }
 }
@@ -148,4 +218,12 @@ pub enum Message{
     Loop(usize),
     Callback(Box<dyn FnOnce(&Handler,&mut Runner) + Send + 'static>),
 
-}
\ No newline at end of file
+}
+impl Message{
+    pub fn lane(&self) -> Lane{
+        match self{
+            Message::Loop(_) => Lane::Background,
+            Message::Callback(_) => Lane::Urgent,
+        }
+    }
+}
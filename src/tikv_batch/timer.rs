@@ -0,0 +1,432 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+
+//region TimerWheel
+enum Task<M> {
+    Once(M),
+    Repeat {
+        interval: u64,
+        msg_gen: Box<dyn FnMut() -> M + Send>,
+    },
+}
+
+struct Entry<M> {
+    id: u64,
+    addr: u64,
+    deadline: u64,
+    task: Task<M>,
+}
+
+/// A hashed timer wheel. Time is divided into ticks, and a timer that is due
+/// at tick `t` is put into slot `t % slots`. A slot holds timers of different
+/// rounds, only the due ones are fired when it's visited.
+pub(crate) struct TimerWheel<M> {
+    slots: Vec<Vec<Entry<M>>>,
+    // The tick that has been advanced to.
+    current: u64,
+    // Slot of every pending timer, by id.
+    slot_of: HashMap<u64, usize>,
+    // Ids of pending timers, by address.
+    by_addr: HashMap<u64, HashSet<u64>>,
+    next_id: u64,
+}
+
+impl<M> TimerWheel<M> {
+    pub(crate) fn new(slot_cnt: usize) -> TimerWheel<M> {
+        assert!(slot_cnt > 0, "a timer wheel needs at least one slot");
+        TimerWheel {
+            slots: (0..slot_cnt).map(|_| vec![]).collect(),
+            current: 0,
+            slot_of: HashMap::new(),
+            by_addr: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Count of pending timers.
+    pub(crate) fn len(&self) -> usize {
+        self.slot_of.len()
+    }
+
+    /// Add a timer that sends `msg` to `addr` at tick `deadline`. Deadlines
+    /// that have passed are moved to the next tick.
+    pub(crate) fn insert_once(&mut self, addr: u64, deadline: u64, msg: M) -> u64 {
+        self.insert(addr, deadline, Task::Once(msg))
+    }
+
+    /// Add a timer that sends a message to `addr` at tick `deadline` and
+    /// every `interval` ticks afterwards.
+    pub(crate) fn insert_repeat(
+        &mut self,
+        addr: u64,
+        deadline: u64,
+        interval: u64,
+        msg_gen: Box<dyn FnMut() -> M + Send>,
+    ) -> u64 {
+        self.insert(addr, deadline, Task::Repeat { interval: interval.max(1), msg_gen })
+    }
+
+    fn insert(&mut self, addr: u64, deadline: u64, task: Task<M>) -> u64 {
+        self.next_id += 1;
+        let id = self.next_id;
+        let deadline = deadline.max(self.current + 1);
+        self.insert_entry(Entry { id, addr, deadline, task });
+        self.by_addr.entry(addr).or_default().insert(id);
+        id
+    }
+
+    fn insert_entry(&mut self, entry: Entry<M>) {
+        let slot = (entry.deadline % self.slots.len() as u64) as usize;
+        self.slot_of.insert(entry.id, slot);
+        self.slots[slot].push(entry);
+    }
+
+    fn forget(&mut self, addr: u64, id: u64) {
+        if let Some(ids) = self.by_addr.get_mut(&addr) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.by_addr.remove(&addr);
+            }
+        }
+    }
+
+    /// Cancel the timer. Returns false if it's fired or cancelled already.
+    pub(crate) fn cancel(&mut self, id: u64) -> bool {
+        let slot = match self.slot_of.remove(&id) {
+            Some(slot) => &mut self.slots[slot],
+            None => return false,
+        };
+        let pos = slot.iter().position(|e| e.id == id).unwrap();
+        let entry = slot.swap_remove(pos);
+        self.forget(entry.addr, id);
+        true
+    }
+
+    /// Cancel all timers of the address. Returns the count of them.
+    pub(crate) fn cancel_addr(&mut self, addr: u64) -> usize {
+        let ids = match self.by_addr.remove(&addr) {
+            Some(ids) => ids,
+            None => return 0,
+        };
+        for id in &ids {
+            if let Some(slot) = self.slot_of.remove(id) {
+                self.slots[slot].retain(|e| e.id != *id);
+            }
+        }
+        ids.len()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        for slot in &mut self.slots {
+            slot.clear();
+        }
+        self.slot_of.clear();
+        self.by_addr.clear();
+    }
+
+    /// Advance to `tick` and return messages of the due timers with their
+    /// addresses, in the order of deadlines. Recurring timers are added back
+    /// for their next deadlines.
+    pub(crate) fn advance(&mut self, tick: u64) -> Vec<(u64, M)> {
+        let mut fired = vec![];
+        if tick <= self.current {
+            return fired;
+        }
+
+        let slot_cnt = self.slots.len() as u64;
+        let mut due = vec![];
+        // Every slot is visited at most once, even if a lot of ticks are skipped.
+        for t in self.current + 1..=tick.min(self.current + slot_cnt) {
+            let slot = &mut self.slots[(t % slot_cnt) as usize];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].deadline <= tick {
+                    due.push(slot.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.current = tick;
+
+        due.sort_unstable_by_key(|e| (e.deadline, e.id));
+        for Entry { id, addr, deadline, task } in due {
+            match task {
+                Task::Once(msg) => {
+                    self.slot_of.remove(&id);
+                    self.forget(addr, id);
+                    fired.push((addr, msg));
+                }
+                Task::Repeat { interval, mut msg_gen } => {
+                    fired.push((addr, msg_gen()));
+                    // Missed ticks are skipped rather than fired at once.
+                    let deadline = (deadline + interval).max(tick + 1);
+                    self.insert_entry(Entry { id, addr, deadline, task: Task::Repeat { interval, msg_gen } });
+                }
+            }
+        }
+        fired
+    }
+}
+
+//endregion
+
+//region Timer
+trait CancelTimer: Send + Sync {
+    fn cancel(&self, id: u64) -> bool;
+}
+
+/// A handle of a scheduled timer, which can be used to cancel it.
+#[derive(Clone)]
+pub struct TimerHandle {
+    id: u64,
+    timer: Weak<dyn CancelTimer>,
+}
+
+impl TimerHandle {
+    fn detached() -> TimerHandle {
+        TimerHandle { id: 0, timer: Weak::<TimerCore<()>>::new() }
+    }
+
+    /// Cancel the timer. Returns false if it's fired, cancelled, or cleaned up
+    /// already. A recurring timer stays pending until it's cancelled.
+    pub fn cancel(&self) -> bool {
+        match self.timer.upgrade() {
+            Some(timer) => timer.cancel(self.id),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Debug for TimerHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TimerHandle").field("id", &self.id).finish()
+    }
+}
+
+struct TimerCore<M> {
+    wheel: Mutex<TimerWheel<M>>,
+    start: Instant,
+    tick: Duration,
+}
+
+impl<M> TimerCore<M> {
+    fn now_tick(&self) -> u64 {
+        (self.start.elapsed().as_nanos() / self.tick.as_nanos()) as u64
+    }
+
+    fn ticks_of(&self, d: Duration) -> u64 {
+        let tick = self.tick.as_nanos();
+        d.as_nanos().div_ceil(tick) as u64
+    }
+
+    // The current tick has partly passed, so a timer is never fired early
+    // only if it's counted from the next tick.
+    fn deadline_after(&self, delay: Duration) -> u64 {
+        self.now_tick() + 1 + self.ticks_of(delay)
+    }
+
+    fn until_next_tick(&self) -> Duration {
+        let tick = self.tick.as_nanos();
+        Duration::from_nanos((tick - self.start.elapsed().as_nanos() % tick) as u64)
+    }
+}
+
+impl<M: Send> CancelTimer for TimerCore<M> {
+    fn cancel(&self, id: u64) -> bool {
+        self.wheel.lock().unwrap().cancel(id)
+    }
+}
+
+#[derive(Default)]
+struct TimerWorker {
+    stopped: bool,
+    // Sending wakes up the thread for a new timer, dropping the sender stops
+    // it.
+    thread: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl TimerWorker {
+    fn wake(&self) {
+        if let Some((tx, _)) = &self.thread {
+            let _ = tx.try_send(());
+        }
+    }
+}
+
+/// Timers of a router. Due timers are fired on a dedicated thread, which is
+/// started on demand, and stopped by `stop` or when the timer is dropped.
+pub(crate) struct Timer<M> {
+    core: Arc<TimerCore<M>>,
+    worker: Mutex<TimerWorker>,
+}
+
+impl<M> Timer<M> {
+    pub(crate) fn new(tick: Duration, slot_cnt: usize) -> Timer<M> {
+        assert!(tick > Duration::default(), "timer tick must be positive");
+        Timer {
+            core: Arc::new(TimerCore {
+                wheel: Mutex::new(TimerWheel::new(slot_cnt)),
+                start: Instant::now(),
+                tick,
+            }),
+            worker: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn cancel_addr(&self, addr: u64) -> usize {
+        self.core.wheel.lock().unwrap().cancel_addr(addr)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.core.wheel.lock().unwrap().len()
+    }
+
+    /// Stop the thread and drop all pending timers. Timers can't be started
+    /// again afterwards.
+    pub(crate) fn stop(&self) {
+        let thread = {
+            let mut worker = self.worker.lock().unwrap();
+            worker.stopped = true;
+            worker.thread.take()
+        };
+        if let Some((tx, handle)) = thread {
+            drop(tx);
+            // The thread may stop the timer on delivery.
+            if handle.thread().id() != thread::current().id() {
+                handle.join().unwrap();
+            }
+        }
+        self.core.wheel.lock().unwrap().clear();
+    }
+}
+
+impl<M: Send + 'static> Timer<M> {
+    /// Start the thread with the function that delivers messages fired at a
+    /// tick, if it's not started yet. Returns false if the timer is stopped.
+    ///
+    /// `deliver` must not keep the timer alive, otherwise the thread is only
+    /// stopped by `stop`.
+    pub(crate) fn start<F, D>(&self, deliver: F) -> bool
+        where F: FnOnce() -> D, D: FnMut(Vec<(u64, M)>) + Send + 'static {
+        let mut worker = self.worker.lock().unwrap();
+        if worker.stopped {
+            return false;
+        }
+        if worker.thread.is_some() {
+            return true;
+        }
+
+        // A single wake-up is enough for any count of new timers.
+        let (tx, rx) = channel::bounded::<()>(1);
+        let core = self.core.clone();
+        let mut deliver = deliver();
+        let handle = thread::Builder::new()
+            .name(crate::thd_name!("batch-timer"))
+            .spawn(move || loop {
+                // Sleep until a timer is added if there is none, a timer added
+                // meanwhile leaves a wake-up in the channel.
+                let idle = core.wheel.lock().unwrap().len() == 0;
+                let res = if idle {
+                    rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                } else {
+                    rx.recv_timeout(core.until_next_tick())
+                };
+                match res {
+                    Ok(()) => continue,
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+                let fired = core.wheel.lock().unwrap().advance(core.now_tick());
+                // The wheel is unlocked, so that timers can be scheduled on delivery.
+                if !fired.is_empty() {
+                    deliver(fired);
+                }
+            })
+            .unwrap();
+        worker.thread = Some((tx, handle));
+        true
+    }
+
+    fn handle(&self, id: u64) -> TimerHandle {
+        let timer: Arc<dyn CancelTimer> = self.core.clone();
+        TimerHandle { id, timer: Arc::downgrade(&timer) }
+    }
+
+    pub(crate) fn schedule_after(&self, addr: u64, delay: Duration, msg: M) -> TimerHandle {
+        // The worker is locked until the timer is added, so that it's never
+        // added after the timer is stopped and cleared.
+        let worker = self.worker.lock().unwrap();
+        if worker.stopped {
+            return TimerHandle::detached();
+        }
+        let deadline = self.core.deadline_after(delay);
+        let id = self.core.wheel.lock().unwrap().insert_once(addr, deadline, msg);
+        worker.wake();
+        self.handle(id)
+    }
+
+    pub(crate) fn schedule_every(
+        &self,
+        addr: u64,
+        interval: Duration,
+        msg_gen: Box<dyn FnMut() -> M + Send>,
+    ) -> TimerHandle {
+        let worker = self.worker.lock().unwrap();
+        if worker.stopped {
+            return TimerHandle::detached();
+        }
+        let deadline = self.core.deadline_after(interval);
+        let interval = self.core.ticks_of(interval);
+        let id = self.core.wheel.lock().unwrap().insert_repeat(addr, deadline, interval, msg_gen);
+        worker.wake();
+        self.handle(id)
+    }
+}
+
+//endregion
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timer_wheel() {
+        let mut wheel = TimerWheel::new(4);
+        wheel.insert_once(1, 2, "a");
+        // Deadlines of different rounds share the slot.
+        wheel.insert_once(2, 6, "b");
+        let c = wheel.insert_once(1, 3, "c");
+        let mut n = 0;
+        wheel.insert_repeat(3, 3, 2, Box::new(move || {
+            n += 1;
+            if n == 1 { "t1" } else { "t2" }
+        }));
+        assert_eq!(wheel.len(), 4);
+
+        assert_eq!(wheel.advance(1), vec![]);
+        assert_eq!(wheel.advance(2), vec![(1, "a")]);
+        assert!(wheel.cancel(c));
+        assert!(!wheel.cancel(c));
+        assert_eq!(wheel.advance(3), vec![(3, "t1")]);
+        // More ticks than slots are skipped, the missed ticks of the
+        // recurring timer are fired only once.
+        assert_eq!(wheel.advance(10), vec![(3, "t2"), (2, "b")]);
+        assert_eq!(wheel.len(), 1);
+        // Passed deadlines are moved to the next tick, timers that are due at
+        // the same tick are fired in the order they are added.
+        wheel.insert_once(1, 0, "d");
+        assert_eq!(wheel.advance(11), vec![(3, "t2"), (1, "d")]);
+
+        wheel.insert_once(3, 20, "e");
+        wheel.insert_once(4, 20, "f");
+        assert_eq!(wheel.cancel_addr(3), 2);
+        assert_eq!(wheel.cancel_addr(3), 0);
+        assert_eq!(wheel.advance(30), vec![(4, "f")]);
+        assert_eq!(wheel.len(), 0);
+    }
+}
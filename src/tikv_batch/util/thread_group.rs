@@ -17,6 +17,11 @@ impl GroupProperties{
     pub fn mark_shutdown(&self){
         self.inner.shutdown.store(true,Ordering::SeqCst);
     }
+
+    #[inline]
+    pub fn is_shutdown(&self) -> bool{
+        self.inner.shutdown.load(Ordering::SeqCst)
+    }
 }
 
 thread_local! {